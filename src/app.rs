@@ -1,60 +1,111 @@
 use ratatui::widgets::ListState;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::sink::OutputDevice;
 
 #[derive(PartialEq)]
 pub enum AppMode {
     Normal,
     Search,
     Play,
+    Queue,
+    Devices,
+    Equalizer,
     Help
 }
 
 pub struct App {
-    pub music_list: Vec<String>,
-    pub filtered_list: Vec<String>,
+    // Each row keeps the file it stands for, as names repeat across albums
+    pub music_list: Vec<PathBuf>,
+    pub filtered_list: Vec<PathBuf>,
     pub list_state: ListState,
+    pub queue_state: ListState,
+    pub device_state: ListState,
+    pub output_devices: Vec<OutputDevice>,
+    pub eq_band: usize,
     pub mode: AppMode,
     pub search_input: String,
-    pub current_song_tags: String
+    pub current_song_tags: String,
+    pub status: Option<(String, Instant)>,
+    pub progress: Option<String>,
+    pub resume_offer: Option<(PathBuf, Duration)>
 }
 
 impl App {
     pub fn new(music_files: &[PathBuf]) -> Self {
-        let music_list: Vec<String> = music_files
-            .iter()
-            .filter_map(|path| 
-                path.file_name()
-                    .and_then(|name| name.to_str())
-                    .map(|s| s.to_string())
-            )
-            .collect();
+        let music_list = music_files.to_vec();
 
         Self {
             filtered_list: music_list.clone(),
             music_list,
             list_state: ListState::default().with_selected(Some(0)),
+            queue_state: ListState::default().with_selected(Some(0)),
+            device_state: ListState::default().with_selected(Some(0)),
+            output_devices: Vec::new(),
+            eq_band: 0,
             mode: AppMode::Normal,
             search_input: String::new(),
             current_song_tags: String::new(),
+            status: None,
+            progress: None,
+            resume_offer: None,
         }
     }
 
     pub fn filter_list(&mut self) {
+        self.filtered_list = self.matching_songs();
+        self.list_state.select(Some(0));
+    }
+
+    fn matching_songs(&self) -> Vec<PathBuf> {
         if self.search_input.is_empty() {
-            self.filtered_list = self.music_list.clone();
+            self.music_list.clone()
         } else {
-            self.filtered_list = self.music_list
+            let search = self.search_input.to_lowercase();
+            self.music_list
                 .iter()
-                .filter(|song| 
-                    song.to_lowercase().contains(&self.search_input.to_lowercase())
-                )
+                .filter(|song| matches_search(song, &search))
                 .cloned()
-                .collect();
+                .collect()
+        }
+    }
+
+    // Files found by a scan still in progress join the list, and the filtered
+    // view if they match the search, without moving the selection
+    pub fn add_music(&mut self, music_files: &[PathBuf]) {
+        let search = self.search_input.to_lowercase();
+
+        self.filtered_list.extend(
+            music_files.iter()
+                .filter(|song| matches_search(song, &search))
+                .cloned()
+        );
+        self.music_list.extend_from_slice(music_files);
+
+        // Rendering an empty list clears its selection
+        if self.list_state.selected().is_none() && !self.filtered_list.is_empty() {
+            self.list_state.select(Some(0));
+        }
+    }
+
+    // Drops a song, or every song under a directory, keeping the selection
+    // on the same row where there still is one
+    pub fn remove_music(&mut self, path: &Path) {
+        self.music_list.retain(|song| !song.starts_with(path));
+        self.filtered_list = self.matching_songs();
+
+        if let Some(selected) = self.list_state.selected()
+            && selected >= self.filtered_list.len()
+        {
+            self.list_state.select(self.filtered_list.len().checked_sub(1));
         }
-        self.list_state.select(Some(0));
     }
 
     pub fn move_down(&mut self) {
+        if self.filtered_list.is_empty() {
+            return;
+        }
         let i = match self.list_state.selected() {
             Some(i) => (i + 1) % self.filtered_list.len(),
             None => 0,
@@ -63,6 +114,9 @@ impl App {
     }
 
     pub fn move_up(&mut self) {
+        if self.filtered_list.is_empty() {
+            return;
+        }
         let i = match self.list_state.selected() {
             Some(i) => (i + self.filtered_list.len() - 1) % self.filtered_list.len(),
             None => 0,
@@ -73,7 +127,7 @@ impl App {
     pub fn half_page_down(&mut self, area_height: usize) {
         let half_page = area_height / 2;
         let current = self.list_state.selected().unwrap_or(0);
-        let new_index = (current + half_page).min(self.filtered_list.len() - 1);
+        let new_index = (current + half_page).min(self.filtered_list.len().saturating_sub(1));
         self.list_state.select(Some(new_index));
     }
 
@@ -84,9 +138,71 @@ impl App {
         self.list_state.select(Some(new_index));
     }
 
-    pub fn get_selected_song(&self) -> Option<&String> {
+    pub fn queue_move_down(&mut self, queue_len: usize) {
+        select_next(&mut self.queue_state, queue_len);
+    }
+
+    pub fn queue_move_up(&mut self, queue_len: usize) {
+        select_previous(&mut self.queue_state, queue_len);
+    }
+
+    // The first entry in the device list stands for the system default
+    pub fn device_move_down(&mut self) {
+        select_next(&mut self.device_state, self.output_devices.len() + 1);
+    }
+
+    pub fn device_move_up(&mut self) {
+        select_previous(&mut self.device_state, self.output_devices.len() + 1);
+    }
+
+    pub fn get_selected_device(&self) -> Option<OutputDevice> {
+        self.device_state
+            .selected()
+            .and_then(|index| index.checked_sub(1))
+            .and_then(|index| self.output_devices.get(index))
+            .cloned()
+    }
+
+    pub fn set_status(&mut self, message: String) {
+        self.status = Some((message, Instant::now()));
+    }
+
+    pub fn get_selected_song(&self) -> Option<&PathBuf> {
         self.list_state
             .selected()
             .and_then(|index| self.filtered_list.get(index))
     }
 }
+
+// What a song is listed and searched by
+pub fn song_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn matches_search(path: &Path, search: &str) -> bool {
+    song_name(path).to_lowercase().contains(search)
+}
+
+fn select_next(state: &mut ListState, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let i = match state.selected() {
+        Some(i) => (i + 1) % len,
+        None => 0,
+    };
+    state.select(Some(i));
+}
+
+fn select_previous(state: &mut ListState, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let i = match state.selected() {
+        Some(i) => (i + len - 1) % len,
+        None => 0,
+    };
+    state.select(Some(i));
+}
@@ -0,0 +1,160 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::config::{Config, LibraryRoot, default_root};
+use crate::music_manipulation::get_music;
+use crate::sink::SinkKind;
+
+#[derive(Parser)]
+#[command(version, about = "A TUI music player with vim bindings")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(value_name = "PATHS", help = "Files or directories to play")]
+    pub paths: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        help = "Read this config file instead of $XDG_CONFIG_HOME/tui_player/config.toml"
+    )]
+    pub config: Option<PathBuf>,
+
+    #[arg(
+        long = "music-dir",
+        global = true,
+        value_name = "DIR",
+        help = "Scan this directory instead of the configured roots (repeatable)"
+    )]
+    pub music_dirs: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "GLOB",
+        help = "Leave out paths matching this glob in every root (repeatable)"
+    )]
+    pub exclude: Vec<String>,
+
+    #[arg(long, global = true, help = "Follow symbolic links in every root")]
+    pub follow_symlinks: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Play the queue in a random order, each track once"
+    )]
+    pub shuffle: bool,
+
+    #[arg(long, global = true, help = "Start playback paused")]
+    pub start_paused: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "SINK",
+        value_parser = parse_output,
+        help = "Play into `null`, `null-fast` or a .wav file instead of a sound card"
+    )]
+    pub output: Option<SinkKind>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Scan the library roots and report what they hold")]
+    Scan,
+    #[command(about = "Print every track in the library")]
+    List {
+        #[arg(long, value_enum, default_value_t = ListFormat::Text)]
+        format: ListFormat,
+    },
+    #[command(about = "Print the tags of a file")]
+    Tags { file: PathBuf },
+    #[command(about = "Play files or directories, or the whole library without any")]
+    Play {
+        #[arg(value_name = "PATHS")]
+        paths: Vec<PathBuf>,
+        #[arg(long, help = "Play from the terminal without the interface")]
+        no_tui: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ListFormat {
+    Text,
+    Json,
+}
+
+impl Cli {
+    // The command line takes precedence over the config, which takes
+    // precedence over the platform's music directory
+    pub fn library_roots(&self, config: &Config) -> Result<Vec<LibraryRoot>, Box<dyn Error>> {
+        let mut roots = if !self.music_dirs.is_empty() {
+            self.music_dirs
+                .iter()
+                .cloned()
+                .map(LibraryRoot::new)
+                .collect()
+        } else if !config.roots.is_empty() {
+            config.roots.clone()
+        } else {
+            vec![default_root()]
+        };
+
+        for root in &mut roots {
+            root.exclude.extend(self.exclude.iter().cloned());
+            root.follow_symlinks |= self.follow_symlinks;
+            root.exclude_set()
+                .map_err(|e| format!("Bad exclude pattern for {}: {}", root.path.display(), e))?;
+        }
+
+        Ok(roots)
+    }
+
+    // The paths asked to be played, if any were. `play` on its own asks for
+    // the whole library, which comes back as no paths at all
+    pub fn play_paths(&self) -> Option<&[PathBuf]> {
+        match &self.command {
+            Some(Command::Play { paths, .. }) => Some(paths),
+            _ if !self.paths.is_empty() => Some(&self.paths),
+            _ => None,
+        }
+    }
+
+    // Directories are played in path order, with the same excludes as the library
+    pub fn expand_paths(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut tracks = Vec::new();
+
+        for path in paths {
+            if path.is_dir() {
+                let mut root = LibraryRoot::new(path.clone());
+                root.exclude = self.exclude.clone();
+                root.follow_symlinks = self.follow_symlinks;
+
+                let mut found = get_music(&[root]);
+                if found.is_empty() {
+                    return Err(format!("No music found in {}", path.display()).into());
+                }
+                found.sort();
+                tracks.extend(found);
+            } else if path.is_file() {
+                tracks.push(path.clone());
+            } else {
+                return Err(format!("{} doesn't exist", path.display()).into());
+            }
+        }
+
+        Ok(tracks)
+    }
+}
+
+fn parse_output(output: &str) -> Result<SinkKind, String> {
+    Ok(match output {
+        "null" => SinkKind::Null { realtime: true },
+        "null-fast" => SinkKind::Null { realtime: false },
+        path => SinkKind::Wav(PathBuf::from(path)),
+    })
+}
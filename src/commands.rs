@@ -0,0 +1,151 @@
+use serde::Serialize;
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::cli::{Cli, ListFormat};
+use crate::config::LibraryRoot;
+use crate::library::{Library, LibraryScan};
+use crate::loudness;
+use crate::music_manipulation::get_music_tags;
+use crate::playback::{AudioPlayer, PlayerEvent};
+use crate::queue::Shuffle;
+use crate::settings::Settings;
+use crate::sink::SinkKind;
+
+// The commands that run without taking over the terminal
+
+pub fn scan(roots: &[LibraryRoot]) -> Result<(), Box<dyn Error>> {
+    let (files, report) = LibraryScan::start(roots.to_vec()).wait()?;
+
+    println!("{} music files", files.len());
+    println!(
+        "{} added, {} updated, {} removed, {} unchanged",
+        report.added, report.updated, report.removed, report.unchanged
+    );
+    if report.failed > 0 {
+        println!("{} files couldn't be read", report.failed);
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ListEntry {
+    path: PathBuf,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+    album_artist: Option<String>,
+    genre: Option<String>,
+    year: Option<u32>,
+    track: Option<u32>,
+    disc: Option<u32>,
+    duration: Option<f64>,
+}
+
+pub fn list(
+    library: &Library,
+    roots: &[LibraryRoot],
+    format: ListFormat,
+) -> Result<(), Box<dyn Error>> {
+    let (files, _) = LibraryScan::start(roots.to_vec()).wait()?;
+    let mut out = io::stdout().lock();
+
+    let written = match format {
+        ListFormat::Text => files
+            .iter()
+            .try_for_each(|path| writeln!(out, "{}", path.display())),
+        ListFormat::Json => {
+            let entries: Vec<ListEntry> = files
+                .into_iter()
+                .map(|path| {
+                    let info = library.track(&path).unwrap_or_default();
+                    ListEntry {
+                        path,
+                        title: info.title,
+                        artist: info.artist,
+                        album: info.album,
+                        album_artist: info.album_artist,
+                        genre: info.genre,
+                        year: info.year,
+                        track: info.track,
+                        disc: info.disc,
+                        duration: info.duration.map(|duration| duration.as_secs_f64()),
+                    }
+                })
+                .collect();
+            writeln!(out, "{}", serde_json::to_string_pretty(&entries)?)
+        }
+    };
+
+    // Piping into something like `head` closes the output early
+    match written {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn tags(file: &Path) -> Result<(), Box<dyn Error>> {
+    let path = file.to_str().ok_or("Path isn't valid UTF-8")?;
+
+    let tags = get_music_tags(path)
+        .map_err(|e| format!("Can't read tags from {}: {}", file.display(), e))?;
+    for (key, value) in tags {
+        println!("{}: {}", key, value);
+    }
+
+    Ok(())
+}
+
+// Plays through the queue once, printing each track as it starts
+pub fn play(cli: &Cli, roots: &[LibraryRoot], paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    let tracks = if paths.is_empty() {
+        LibraryScan::start(roots.to_vec()).wait()?.0
+    } else {
+        cli.expand_paths(paths)?
+    };
+    if tracks.is_empty() {
+        return Err("Nothing to play".into());
+    }
+
+    loudness::preload_cache();
+    let settings = Settings::load();
+    let sink = cli
+        .output
+        .clone()
+        .unwrap_or_else(|| SinkKind::Device(settings.output_device.clone()));
+    let mut player = AudioPlayer::new(sink);
+    player.set_fade(Duration::from_millis(settings.fade_ms as u64));
+    player.set_crossfade(settings.crossfade);
+    player.set_replay_gain_mode(settings.replay_gain);
+    player.set_equalizer(settings.equalizer);
+    if cli.shuffle {
+        player.set_shuffle(Shuffle::Bag);
+    }
+
+    // Tracks that can't be opened are reported and skipped by the player
+    player.play_tracks(tracks, false);
+    loop {
+        player.advance_if_finished();
+
+        for event in player.poll_events() {
+            match event {
+                PlayerEvent::TrackStarted(_) => println!("{}", event),
+                PlayerEvent::Error(_) | PlayerEvent::DeviceLost => eprintln!("{}", event),
+                _ => {}
+            }
+        }
+
+        if !player.is_playing() {
+            break;
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    player.stop();
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The user's own configuration, which the app only ever reads
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(rename = "root")]
+    pub roots: Vec<LibraryRoot>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct LibraryRoot {
+    pub path: PathBuf,
+    // Globs matched against paths relative to the root, e.g. "Podcasts/**"
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub follow_symlinks: bool,
+}
+
+impl LibraryRoot {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            exclude: Vec::new(),
+            follow_symlinks: false,
+        }
+    }
+
+    pub fn exclude_set(&self) -> Result<GlobSet, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.exclude {
+            builder.add(Glob::new(pattern)?);
+        }
+        builder.build()
+    }
+}
+
+impl Config {
+    // A missing config file at the default location just means the defaults,
+    // but one asked for explicitly has to exist
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let contents = match path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Can't read {}: {}", path.display(), e))?,
+            None => match config_path().and_then(|path| fs::read_to_string(path).ok()) {
+                Some(contents) => contents,
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let mut config: Config = toml::from_str(&contents)?;
+        for root in &mut config.roots {
+            root.path = expand_home(&root.path);
+        }
+
+        Ok(config)
+    }
+}
+
+// Without any roots configured, the platform's music directory is scanned
+pub fn default_root() -> LibraryRoot {
+    let path = dirs::audio_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join("Music")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    LibraryRoot::new(path)
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("tui_player").join("config.toml"))
+}
@@ -0,0 +1,243 @@
+use serde::{Deserialize, Serialize};
+
+pub const BANDS: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+pub const MAX_GAIN: f32 = 12.0;
+
+// Roughly one octave wide, so neighbouring bands blend into each other
+const BAND_Q: f64 = 1.41;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    Flat,
+    BassBoost,
+    Vocal,
+    Rock,
+    Classical,
+    Electronic,
+    Jazz,
+    Custom,
+}
+
+impl Preset {
+    const CYCLE: [Preset; 7] = [
+        Preset::Flat,
+        Preset::BassBoost,
+        Preset::Vocal,
+        Preset::Rock,
+        Preset::Classical,
+        Preset::Electronic,
+        Preset::Jazz,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Flat => "flat",
+            Preset::BassBoost => "bass boost",
+            Preset::Vocal => "vocal",
+            Preset::Rock => "rock",
+            Preset::Classical => "classical",
+            Preset::Electronic => "electronic",
+            Preset::Jazz => "jazz",
+            Preset::Custom => "custom",
+        }
+    }
+
+    fn gains(self) -> [f32; 10] {
+        match self {
+            Preset::Flat | Preset::Custom => [0.0; 10],
+            Preset::BassBoost => [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Preset::Vocal => [-2.0, -2.0, -1.0, 0.0, 2.0, 3.0, 3.0, 2.0, 0.0, -1.0],
+            Preset::Rock => [4.0, 3.0, 2.0, 0.0, -1.0, -1.0, 0.0, 2.0, 3.0, 4.0],
+            Preset::Classical => [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -1.0, -2.0, -2.0, -3.0],
+            Preset::Electronic => [5.0, 4.0, 1.0, 0.0, -2.0, 1.0, 0.0, 1.0, 4.0, 5.0],
+            Preset::Jazz => [3.0, 2.0, 1.0, 2.0, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0],
+        }
+    }
+
+    // The preset a track's genre tag suggests, if any
+    fn for_genre(genre: &str) -> Option<Preset> {
+        let genre = genre.to_lowercase();
+        let matches = |words: &[&str]| words.iter().any(|word| genre.contains(word));
+
+        if matches(&["hip hop", "hip-hop", "rap", "reggae", "dub"]) {
+            Some(Preset::BassBoost)
+        } else if matches(&["electro", "dance", "techno", "house", "trance", "edm"]) {
+            Some(Preset::Electronic)
+        } else if matches(&["rock", "metal", "punk", "grunge"]) {
+            Some(Preset::Rock)
+        } else if matches(&["classical", "orchestra", "opera", "baroque"]) {
+            Some(Preset::Classical)
+        } else if matches(&["jazz", "blues", "swing"]) {
+            Some(Preset::Jazz)
+        } else if matches(&["pop", "vocal", "folk", "singer", "spoken"]) {
+            Some(Preset::Vocal)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EqSettings {
+    pub enabled: bool,
+    pub preset: Preset,
+    pub gains: [f32; 10],
+    pub genre_presets: bool,
+}
+
+impl Default for EqSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            preset: Preset::Flat,
+            gains: [0.0; 10],
+            genre_presets: false,
+        }
+    }
+}
+
+impl EqSettings {
+    pub fn cycle_preset(&mut self) {
+        let index = Preset::CYCLE
+            .iter()
+            .position(|&preset| preset == self.preset)
+            .map_or(0, |index| (index + 1) % Preset::CYCLE.len());
+
+        self.preset = Preset::CYCLE[index];
+        self.gains = self.preset.gains();
+    }
+
+    pub fn adjust_band(&mut self, band: usize, amount: f32) {
+        if let Some(gain) = self.gains.get_mut(band) {
+            *gain = (*gain + amount).clamp(-MAX_GAIN, MAX_GAIN);
+            self.preset = Preset::Custom;
+        }
+    }
+
+    // Band gains for a track, or None when the equalizer is off
+    pub fn gains_for(&self, genre: Option<&str>) -> Option<[f32; 10]> {
+        if !self.enabled {
+            return None;
+        }
+
+        let genre_preset = genre
+            .filter(|_| self.genre_presets)
+            .and_then(Preset::for_genre);
+
+        Some(genre_preset.map_or(self.gains, Preset::gains))
+    }
+}
+
+pub struct Equalizer {
+    rate: f64,
+    channels: usize,
+    gains: Option<[f32; 10]>,
+    // One filter per band for each channel
+    filters: Vec<Vec<Biquad>>,
+    // Cut applied up front so boosted bands don't clip
+    headroom: f32,
+}
+
+impl Equalizer {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        Self {
+            rate: rate as f64,
+            channels,
+            gains: None,
+            filters: Vec::new(),
+            headroom: 1.0,
+        }
+    }
+
+    // Filter state is kept across changes so adjusting a band doesn't click
+    pub fn set_gains(&mut self, gains: Option<[f32; 10]>) {
+        if gains == self.gains {
+            return;
+        }
+        self.gains = gains;
+
+        let Some(gains) = gains else {
+            return;
+        };
+
+        let bands: Vec<Biquad> = BANDS
+            .iter()
+            .zip(gains)
+            .map(|(&frequency, gain)| Biquad::peaking(self.rate, frequency as f64, gain as f64))
+            .collect();
+
+        if self.filters.is_empty() {
+            self.filters = vec![bands; self.channels];
+        } else {
+            for channel in self.filters.iter_mut() {
+                for (filter, band) in channel.iter_mut().zip(&bands) {
+                    filter.b = band.b;
+                    filter.a = band.a;
+                }
+            }
+        }
+
+        let boost = gains.iter().copied().fold(0.0, f32::max);
+        self.headroom = 10f32.powf(-boost / 20.0);
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.gains.is_none() {
+            return;
+        }
+
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (sample, filters) in frame.iter_mut().zip(self.filters.iter_mut()) {
+                let mut value = (*sample * self.headroom) as f64;
+                for filter in filters.iter_mut() {
+                    value = filter.process(value);
+                }
+                *sample = value as f32;
+            }
+        }
+    }
+}
+
+// Transposed direct form II, in f64 so the low bands stay stable
+#[derive(Clone, Copy)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    // Coefficients normalized so a0 is 1
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, z: [0.0; 2] }
+    }
+
+    // RBJ cookbook peaking filter
+    fn peaking(rate: f64, frequency: f64, gain: f64) -> Self {
+        let a = 10f64.powf(gain / 40.0);
+        // Bands above the Nyquist frequency are pinned just below it
+        let w0 = 2.0 * std::f64::consts::PI * frequency.min(rate * 0.45) / rate;
+        let alpha = w0.sin() / (2.0 * BAND_Q);
+        let a0 = 1.0 + alpha / a;
+
+        Self::new(
+            [
+                (1.0 + alpha * a) / a0,
+                -2.0 * w0.cos() / a0,
+                (1.0 - alpha * a) / a0,
+            ],
+            [-2.0 * w0.cos() / a0, (1.0 - alpha / a) / a0],
+        )
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
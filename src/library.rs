@@ -0,0 +1,422 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::config::LibraryRoot;
+use crate::music_manipulation::{TrackInfo, get_track_info, music_files};
+use crate::playback::EXIT_NOW;
+
+// Bumping this throws the index away and rebuilds it on the next scan
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tracks (
+        path TEXT PRIMARY KEY,
+        mtime INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        title TEXT,
+        artist TEXT,
+        album TEXT,
+        album_artist TEXT,
+        genre TEXT,
+        year INTEGER,
+        track INTEGER,
+        disc INTEGER,
+        duration_ms INTEGER
+    )";
+
+#[derive(Default)]
+pub struct ScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    // Files that are listed but couldn't be probed for tags
+    pub failed: usize,
+}
+
+// An on-disk index of every file in the library with its tags, so a rescan
+// only has to read the files that changed since the last one
+pub struct Library {
+    conn: Connection,
+}
+
+impl Library {
+    pub fn open() -> Result<Self, Box<dyn Error>> {
+        let conn = match library_path() {
+            Some(path) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                Connection::open(&path)
+                    .map_err(|e| format!("Can't open {}: {}", path.display(), e))?
+            }
+            None => Connection::open_in_memory()?,
+        };
+
+        // A scan writes from its own connection while the interface reads, and
+        // another instance may be rescanning at the same time
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            conn.execute_batch("DROP TABLE IF EXISTS tracks")?;
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self { conn })
+    }
+
+    fn known(&self) -> rusqlite::Result<HashMap<String, (i64, i64)>> {
+        self.conn
+            .prepare("SELECT path, mtime, size FROM tracks")?
+            .query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?
+            .collect()
+    }
+
+    // Reads a single file's tags into the index, unless it's unchanged since
+    // they were last read. Returns whether they were read
+    pub fn update(&self, path: &Path) -> rusqlite::Result<bool> {
+        let key = path_key(path);
+        let stamp = file_stamp(path);
+        if self.stamp(&key)? == Some(stamp) {
+            return Ok(false);
+        }
+
+        let info = get_track_info(&key).unwrap_or_default();
+        store(&self.conn, &key, stamp, &info)?;
+        Ok(true)
+    }
+
+    fn stamp(&self, key: &str) -> rusqlite::Result<Option<(i64, i64)>> {
+        self.conn
+            .query_row(
+                "SELECT mtime, size FROM tracks WHERE path = ?1",
+                [key],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    // Forgets a file, or everything under a directory
+    pub fn remove(&self, path: &Path) -> rusqlite::Result<()> {
+        let key = path_key(path);
+        let prefix = path_key(&path.join(""));
+
+        self.conn.execute(
+            "DELETE FROM tracks WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+            [key, prefix],
+        )?;
+        Ok(())
+    }
+
+    pub fn track(&self, path: &Path) -> Option<TrackInfo> {
+        self.conn
+            .query_row(
+                "SELECT title, artist, album, album_artist, genre, year, track, disc, duration_ms
+                 FROM tracks WHERE path = ?1",
+                [path_key(path)],
+                |row| {
+                    Ok(TrackInfo {
+                        title: row.get(0)?,
+                        artist: row.get(1)?,
+                        album: row.get(2)?,
+                        album_artist: row.get(3)?,
+                        genre: row.get(4)?,
+                        year: row.get(5)?,
+                        track: row.get(6)?,
+                        disc: row.get(7)?,
+                        duration: row
+                            .get::<_, Option<i64>>(8)?
+                            .map(|ms| Duration::from_millis(ms as u64)),
+                    })
+                },
+            )
+            .optional()
+            .ok()
+            .flatten()
+    }
+}
+
+// Walks the roots on a background thread, streaming every file it finds,
+// while a pool of workers re-reads the tags of files that changed since the
+// last scan. Files gone from the roots are forgotten once the walk completes
+pub struct LibraryScan {
+    found: Receiver<PathBuf>,
+    progress: Arc<ScanProgress>,
+    handle: thread::JoinHandle<Result<ScanReport, String>>,
+}
+
+#[derive(Default)]
+struct ScanProgress {
+    found: AtomicUsize,
+    tagged: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+// Everything a worker needs to store a file, and what it read
+struct TagJob {
+    key: String,
+    stamp: (i64, i64),
+    known: bool,
+}
+
+impl LibraryScan {
+    pub fn start(roots: Vec<LibraryRoot>) -> Self {
+        let (found_tx, found) = mpsc::channel();
+        let progress = Arc::new(ScanProgress::default());
+
+        let thread_progress = Arc::clone(&progress);
+        let handle = thread::spawn(move || {
+            run_scan(&roots, &found_tx, &thread_progress).map_err(|e| e.to_string())
+        });
+
+        Self {
+            found,
+            progress,
+            handle,
+        }
+    }
+
+    // Files found since the last call
+    pub fn new_files(&self) -> Vec<PathBuf> {
+        self.found.try_iter().collect()
+    }
+
+    // Files found, files whose tags are known and files that couldn't be read
+    pub fn progress(&self) -> (usize, usize, usize) {
+        (
+            self.progress.found.load(Ordering::Relaxed),
+            self.progress.tagged.load(Ordering::Relaxed),
+            self.progress.failed.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    pub fn finish(self) -> Result<ScanReport, Box<dyn Error>> {
+        match self.handle.join() {
+            Ok(report) => Ok(report?),
+            Err(_) => Err("Library scan panicked".into()),
+        }
+    }
+
+    // Blocks until the scan is done, returning every file in the order found
+    pub fn wait(self) -> Result<(Vec<PathBuf>, ScanReport), Box<dyn Error>> {
+        let files = self.found.iter().collect();
+        Ok((files, self.finish()?))
+    }
+}
+
+fn run_scan(
+    roots: &[LibraryRoot],
+    found: &Sender<PathBuf>,
+    progress: &ScanProgress,
+) -> Result<ScanReport, Box<dyn Error>> {
+    let library = Library::open()?;
+    let conn = &library.conn;
+    let known = library.known()?;
+    let mut report = ScanReport::default();
+
+    let (job_tx, job_rx) = mpsc::channel::<TagJob>();
+    let (done_tx, done_rx) = mpsc::channel();
+    let job_rx = Arc::new(Mutex::new(job_rx));
+
+    let workers = thread::available_parallelism().map_or(4, |count| count.get());
+    for _ in 0..workers {
+        let job_rx = Arc::clone(&job_rx);
+        let done_tx = done_tx.clone();
+
+        thread::spawn(move || {
+            loop {
+                let job = job_rx.lock().unwrap().recv();
+                let Ok(job) = job else {
+                    break;
+                };
+                if EXIT_NOW.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let info = get_track_info(&job.key);
+                if done_tx.send((job, info.ok())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(done_tx);
+
+    // Only this thread writes, committing now and then so quitting halfway
+    // keeps what's been read
+    let mut pending = 0;
+    let mut store_done = |(job, info): (TagJob, Option<TrackInfo>)| -> rusqlite::Result<()> {
+        if info.is_none() {
+            report.failed += 1;
+            progress.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        if job.known {
+            report.updated += 1;
+        } else {
+            report.added += 1;
+        }
+
+        if pending == 0 {
+            conn.execute_batch("BEGIN")?;
+        }
+        store(conn, &job.key, job.stamp, &info.unwrap_or_default())?;
+        progress.tagged.fetch_add(1, Ordering::Relaxed);
+
+        pending += 1;
+        if pending == 500 {
+            conn.execute_batch("COMMIT")?;
+            pending = 0;
+        }
+        Ok(())
+    };
+
+    let mut seen = HashSet::new();
+    for path in music_files(roots) {
+        if EXIT_NOW.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let key = path_key(&path);
+        let stamp = file_stamp(&path);
+        seen.insert(key.clone());
+        progress.found.fetch_add(1, Ordering::Relaxed);
+
+        let previous = known.get(&key);
+        if previous == Some(&stamp) {
+            report.unchanged += 1;
+            progress.tagged.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let _ = job_tx.send(TagJob {
+                key,
+                stamp,
+                known: previous.is_some(),
+            });
+        }
+        let _ = found.send(path);
+
+        for done in done_rx.try_iter() {
+            store_done(done)?;
+        }
+    }
+
+    drop(job_tx);
+    for done in done_rx.iter() {
+        store_done(done)?;
+    }
+    if pending > 0 {
+        conn.execute_batch("COMMIT")?;
+    }
+
+    if EXIT_NOW.load(Ordering::SeqCst) {
+        return Ok(report);
+    }
+
+    // Only what was looked for can be missing, so entries under other roots
+    // are left alone
+    let under_roots = |key: &str| {
+        roots
+            .iter()
+            .any(|root| Path::new(key).starts_with(&root.path))
+    };
+    for key in known.keys() {
+        if !seen.contains(key) && under_roots(key) {
+            conn.execute("DELETE FROM tracks WHERE path = ?1", [key])?;
+            report.removed += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+fn store(
+    conn: &Connection,
+    key: &str,
+    stamp: (i64, i64),
+    info: &TrackInfo,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO tracks
+         (path, mtime, size, title, artist, album, album_artist, genre, year, track, disc, duration_ms)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            key,
+            stamp.0,
+            stamp.1,
+            info.title,
+            info.artist,
+            info.album,
+            info.album_artist,
+            info.genre,
+            info.year,
+            info.track,
+            info.disc,
+            info.duration.map(|duration| duration.as_millis() as i64),
+        ],
+    )?;
+    Ok(())
+}
+
+// Paths are stored as text, like everywhere else tags are read
+fn path_key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+// Modification time in nanoseconds and size in bytes, which together stand
+// in for the file's contents
+fn file_stamp(path: &Path) -> (i64, i64) {
+    let Ok(metadata) = fs::metadata(path) else {
+        return (0, 0);
+    };
+
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_nanos() as i64);
+
+    (mtime, metadata.len() as i64)
+}
+
+// The index can always be rebuilt from the files, so it lives with the cache
+fn library_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("tui_player").join("library.db"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_files_are_not_read_again() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        let library = Library { conn };
+
+        let path =
+            std::env::temp_dir().join(format!("tui_player_test_{}_stamp.mp3", std::process::id()));
+        fs::write(&path, b"not audio").unwrap();
+
+        assert!(library.update(&path).unwrap());
+        assert!(!library.update(&path).unwrap());
+
+        fs::write(&path, b"not audio either").unwrap();
+        assert!(library.update(&path).unwrap());
+
+        let _ = fs::remove_file(&path);
+    }
+}
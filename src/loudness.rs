@@ -0,0 +1,551 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use symphonia::core::{
+    audio::{Channels, SampleBuffer},
+    codecs::DecoderOptions,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::equalizer::Biquad;
+use crate::music_manipulation::{ReplayGain, get_replay_gain, write_replay_gain};
+use crate::playback::EXIT_NOW;
+use crate::toml_file;
+
+// ReplayGain 2.0 plays everything back at -18 LUFS
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+// The whole cache is rewritten on every save, so a long scan only saves
+// now and then rather than after every few files
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Loudness {
+    pub integrated: f64,
+    pub true_peak: f64,
+    modified: u64,
+    size: u64,
+}
+
+impl Loudness {
+    pub fn gain(&self) -> f32 {
+        (REFERENCE_LOUDNESS - self.integrated) as f32
+    }
+}
+
+// ReplayGain tags, or the analysed loudness for files that have none
+pub fn replay_gain(path: &Path) -> ReplayGain {
+    fill_in(path, get_replay_gain(path.to_str().unwrap_or("")))
+}
+
+// For tags that have already been read
+pub fn fill_in(path: &Path, mut replay_gain: ReplayGain) -> ReplayGain {
+    if replay_gain.track_gain.is_none()
+        && let Some(loudness) = cached(path)
+    {
+        replay_gain.track_gain = Some(loudness.gain());
+        replay_gain.track_peak = Some(loudness.true_peak as f32);
+    }
+
+    replay_gain
+}
+
+pub fn cached(path: &Path) -> Option<Loudness> {
+    let (modified, size) = file_stamp(path)?;
+    let cache = cache().lock().unwrap();
+
+    cache
+        .tracks
+        .get(path.to_str()?)
+        .filter(|loudness| loudness.modified == modified && loudness.size == size)
+        .copied()
+}
+
+// Analyses every file that has neither ReplayGain tags nor an up-to-date
+// cache entry, one after another on a background thread
+pub struct LoudnessScan {
+    done: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    total: usize,
+    handle: thread::JoinHandle<()>,
+}
+
+impl LoudnessScan {
+    pub fn start(files: Vec<PathBuf>, write_tags: bool) -> Self {
+        let done = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let total = files.len();
+
+        let thread_done = Arc::clone(&done);
+        let thread_failed = Arc::clone(&failed);
+
+        let handle = thread::spawn(move || {
+            let mut saved = Instant::now();
+
+            for path in &files {
+                if EXIT_NOW.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if replay_gain(path).track_gain.is_none() {
+                    match analyze(path) {
+                        Some(loudness) => {
+                            store(path, loudness);
+
+                            if write_tags
+                                && write_replay_gain(
+                                    path.to_str().unwrap_or(""),
+                                    loudness.gain(),
+                                    loudness.true_peak as f32,
+                                )
+                                .is_err()
+                            {
+                                thread_failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        None => {
+                            thread_failed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+
+                // Saved as it goes so quitting halfway keeps what's been done
+                if saved.elapsed() >= SAVE_INTERVAL {
+                    let _ = cache().lock().unwrap().save();
+                    saved = Instant::now();
+                }
+                thread_done.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let _ = cache().lock().unwrap().save();
+        });
+
+        Self {
+            done,
+            failed,
+            total,
+            handle,
+        }
+    }
+
+    pub fn progress(&self) -> (usize, usize) {
+        (self.done.load(Ordering::Relaxed), self.total)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+}
+
+// Integrated loudness and true peak of a whole file, per EBU R128 / BS.1770
+pub fn analyze(path: &Path) -> Option<Loudness> {
+    let (modified, size) = file_stamp(path)?;
+
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+    let mut format = probed.format;
+
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .ok()?;
+
+    let mut meter: Option<Meter> = None;
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+
+    while let Ok(packet) = format.next_packet() {
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let audio_buf = match decoder.decode(&packet) {
+            Ok(audio_buf) => audio_buf,
+            Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
+            Err(_) => break,
+        };
+
+        let spec = *audio_buf.spec();
+        let meter = meter.get_or_insert_with(|| Meter::new(spec.rate, spec.channels));
+
+        let sample_buf = match &mut sample_buf {
+            Some(sample_buf) if sample_buf.capacity() >= audio_buf.capacity() => sample_buf,
+            sample_buf => {
+                sample_buf.insert(SampleBuffer::<f32>::new(audio_buf.capacity() as u64, spec))
+            }
+        };
+        sample_buf.copy_interleaved_ref(audio_buf);
+
+        meter.process(sample_buf.samples());
+    }
+
+    let meter = meter?;
+    Some(Loudness {
+        integrated: meter.integrated()?,
+        true_peak: meter.peak,
+        modified,
+        size,
+    })
+}
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+struct Meter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    // Sums of squared filtered samples over 100 ms, per channel
+    step_frames: usize,
+    step_sums: Vec<f64>,
+    step_count: usize,
+    steps: Vec<Vec<f64>>,
+    blocks: Vec<f64>,
+    interpolator: Vec<f64>,
+    history: Vec<Vec<f64>>,
+    peak: f64,
+}
+
+impl Meter {
+    fn new(rate: u32, layout: Channels) -> Self {
+        let channels = layout.count();
+
+        // Surround channels count for more and the LFE isn't measured at all
+        let weights = layout
+            .iter()
+            .map(|channel| {
+                if channel == Channels::LFE1 {
+                    0.0
+                } else if channel.intersects(
+                    Channels::SIDE_LEFT
+                        | Channels::SIDE_RIGHT
+                        | Channels::REAR_LEFT
+                        | Channels::REAR_RIGHT,
+                ) {
+                    1.41
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+
+        Self {
+            channels,
+            weights,
+            filters: vec![k_weighting(rate as f64); channels],
+            step_frames: (rate as usize / 10).max(1),
+            step_sums: vec![0.0; channels],
+            step_count: 0,
+            steps: Vec::new(),
+            blocks: Vec::new(),
+            interpolator: interpolator(),
+            history: vec![vec![0.0; TAPS_PER_PHASE]; channels],
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                let sample = sample as f64;
+
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let filtered = high_pass.process(shelf.process(sample));
+                self.step_sums[channel] += filtered * filtered;
+
+                self.track_peak(channel, sample);
+            }
+
+            self.step_count += 1;
+            if self.step_count == self.step_frames {
+                self.finish_step();
+            }
+        }
+    }
+
+    // Gating blocks are 400 ms long and start every 100 ms
+    fn finish_step(&mut self) {
+        let means = self
+            .step_sums
+            .iter()
+            .map(|sum| sum / self.step_frames as f64)
+            .collect();
+        self.steps.push(means);
+        self.step_sums.fill(0.0);
+        self.step_count = 0;
+
+        if self.steps.len() >= 4 {
+            let recent = &self.steps[self.steps.len() - 4..];
+            let power = (0..self.channels)
+                .map(|channel| {
+                    let mean = recent.iter().map(|step| step[channel]).sum::<f64>() / 4.0;
+                    self.weights[channel] * mean
+                })
+                .sum();
+            self.blocks.push(power);
+        }
+    }
+
+    fn integrated(&self) -> Option<f64> {
+        let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+        let absolute: Vec<f64> = self
+            .blocks
+            .iter()
+            .copied()
+            .filter(|&power| loudness(power) > -70.0)
+            .collect();
+        if absolute.is_empty() {
+            return None;
+        }
+
+        let threshold = loudness(mean(&absolute)) - 10.0;
+        let relative: Vec<f64> = absolute
+            .into_iter()
+            .filter(|&power| loudness(power) > threshold)
+            .collect();
+        if relative.is_empty() {
+            return None;
+        }
+
+        Some(loudness(mean(&relative)))
+    }
+
+    // Peaks between samples show up once the signal is upsampled
+    fn track_peak(&mut self, channel: usize, sample: f64) {
+        let history = &mut self.history[channel];
+        history.rotate_right(1);
+        history[0] = sample;
+
+        self.peak = self.peak.max(sample.abs());
+
+        for phase in 0..OVERSAMPLING {
+            let value: f64 = history
+                .iter()
+                .enumerate()
+                .map(|(tap, &past)| self.interpolator[tap * OVERSAMPLING + phase] * past)
+                .sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+// Windowed-sinc lowpass at the original Nyquist frequency, laid out so tap
+// `tap * OVERSAMPLING + phase` applies to the sample `tap` steps back
+fn interpolator() -> Vec<f64> {
+    let length = TAPS_PER_PHASE * OVERSAMPLING;
+    let centre = (length - 1) as f64 / 2.0;
+
+    (0..length)
+        .map(|n| {
+            let x = (n as f64 - centre) / OVERSAMPLING as f64;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+            };
+            let window =
+                0.5 - 0.5 * (2.0 * std::f64::consts::PI * n as f64 / (length - 1) as f64).cos();
+            sinc * window
+        })
+        .collect()
+}
+
+// The BS.1770 pre-filter (a high shelf) and RLB high-pass, worked out for
+// any sample rate rather than just the tabulated 48 kHz coefficients
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let pi = std::f64::consts::PI;
+
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (pi * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (pi * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct LoudnessCache {
+    tracks: HashMap<String, Loudness>,
+}
+
+impl LoudnessCache {
+    fn load() -> Self {
+        toml_file::load(cache_path().as_deref())
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = cache_path().ok_or("No cache directory found")?;
+        toml_file::save(&path, self)
+    }
+}
+
+// Reads the cache in at startup on a thread of its own, so opening the first
+// track doesn't have to wait for the file
+pub fn preload_cache() {
+    thread::spawn(cache);
+}
+
+fn cache() -> &'static Mutex<LoudnessCache> {
+    static CACHE: OnceLock<Mutex<LoudnessCache>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(LoudnessCache::load()))
+}
+
+fn store(path: &Path, loudness: Loudness) {
+    if let Some(key) = path.to_str() {
+        cache()
+            .lock()
+            .unwrap()
+            .tracks
+            .insert(key.to_string(), loudness);
+    }
+}
+
+fn cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("tui_player").join("loudness.toml"))
+}
+
+// Cache entries are dropped once the file changes
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+
+    Some((modified, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    // A stereo sine at `dbfs` peak level, in the same sample layout the decoder hands over
+    fn sine(frequency: f64, dbfs: f64, seconds: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        let frames = (seconds * RATE as f64) as usize;
+
+        (0..frames)
+            .flat_map(|frame| {
+                let value = amplitude
+                    * (2.0 * std::f64::consts::PI * frequency * frame as f64 / RATE as f64).sin();
+                [value as f32; 2]
+            })
+            .collect()
+    }
+
+    fn stereo_meter() -> Meter {
+        Meter::new(RATE, Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
+    }
+
+    // EBU Tech 3341 case 1: a stereo sine near 1 kHz reads its own peak level
+    // in LUFS, since the K-weighting adds back what the -0.691 offset takes off
+    #[test]
+    fn sines_read_their_level_in_lufs() {
+        for dbfs in [-20.0, -23.0] {
+            let mut meter = stereo_meter();
+            meter.process(&sine(997.0, dbfs, 3.0));
+
+            let integrated = meter.integrated().unwrap();
+            assert!(
+                (integrated - dbfs).abs() < 0.1,
+                "{} read as {}",
+                dbfs,
+                integrated
+            );
+        }
+    }
+
+    // Shortened EBU Tech 3341 case 4: silence-level parts fall to the absolute
+    // gate and the quiet parts to the relative one, leaving the loud part.
+    // Without the gates this would read about -24.2 LUFS
+    #[test]
+    fn quiet_parts_are_gated_out() {
+        let mut meter = stereo_meter();
+        for (dbfs, seconds) in [
+            (-72.0, 1.0),
+            (-36.0, 2.0),
+            (-23.0, 12.0),
+            (-36.0, 2.0),
+            (-72.0, 1.0),
+        ] {
+            meter.process(&sine(997.0, dbfs, seconds));
+        }
+
+        let integrated = meter.integrated().unwrap();
+        assert!((integrated + 23.0).abs() < 0.2, "read as {}", integrated);
+    }
+
+    // A sine at a quarter of the rate, a quarter cycle out of phase, only
+    // ever gets sampled at 0.707 of its peak
+    #[test]
+    fn true_peak_finds_the_peak_between_samples() {
+        let samples: Vec<f32> = (0..RATE as usize)
+            .flat_map(|frame| {
+                let phase =
+                    std::f64::consts::FRAC_PI_2 * frame as f64 + std::f64::consts::FRAC_PI_4;
+                [(0.5 * phase.sin()) as f32; 2]
+            })
+            .collect();
+
+        let mut meter = stereo_meter();
+        meter.process(&samples);
+
+        assert!(
+            meter.peak > 0.48 && meter.peak < 0.51,
+            "peak read as {}",
+            meter.peak
+        );
+    }
+}
@@ -1,15 +1,17 @@
 use crossterm::{
-    event::{self, Event, KeyCode, KeyModifiers},
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
     execute,
     terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
 use ratatui::{
     prelude::*,
-    widgets::{Block, Borders, Gauge, List, ListItem, Paragraph, Wrap},
+    widgets::{Bar, BarChart, BarGroup, Block, Borders, Gauge, List, ListItem, Paragraph, Wrap},
 };
+use std::collections::HashSet;
 use std::error::Error;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 mod music_manipulation;
 use music_manipulation::*;
@@ -20,146 +22,611 @@ use app::*;
 mod playback;
 use playback::*;
 
+mod queue;
+use queue::{Repeat, Shuffle};
+
+mod resample;
+
+mod settings;
+use settings::Settings;
+
+mod sink;
+use sink::{SinkKind, list_output_devices};
+
+mod loudness;
+use loudness::LoudnessScan;
+
+mod equalizer;
+use equalizer::{BANDS, MAX_GAIN};
+
+mod stretch;
+
+mod resume;
+use resume::{ResumeState, SavedQueue};
+
+mod session;
+use session::Session;
+
+mod toml_file;
+
+mod config;
+use config::Config;
+
+mod cli;
+use clap::Parser;
+use cli::{Cli, Command};
+
+mod commands;
+
+mod library;
+use library::{Library, LibraryScan};
+
+mod watch;
+use watch::{LibraryChange, LibraryWatcher};
+
 fn main() -> Result<(), Box<dyn Error>> {
+    // Problems with the config are reported before the terminal is taken over
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref())?;
+    let roots = cli.library_roots(&config)?;
+
+    match &cli.command {
+        Some(Command::Scan) => return commands::scan(&roots),
+        Some(Command::List { format }) => {
+            return commands::list(&Library::open()?, &roots, *format);
+        }
+        Some(Command::Tags { file }) => return commands::tags(file),
+        Some(Command::Play {
+            paths,
+            no_tui: true,
+        }) => return commands::play(&cli, &roots, paths),
+        _ => {}
+    }
+
+    // Paths given on the command line take the place of the last session's queue
+    let requested = cli
+        .play_paths()
+        .map(|paths| cli.expand_paths(paths))
+        .transpose()?;
+
+    let library = Library::open()?;
+
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen)?;
     let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
 
-    let music_dir = "/home/buzzkill/Music/";
-    let music_files = get_music(music_dir);
+    // The library fills in as the scan finds files, and keeps up with
+    // changes to the roots from then on
+    let mut library_scan = Some(LibraryScan::start(roots.clone()));
+    let watcher = LibraryWatcher::start(roots.clone());
+    let mut app = App::new(&[]);
+
+    loudness::preload_cache();
+    let mut settings = Settings::load();
+    let sink = cli
+        .output
+        .clone()
+        .unwrap_or_else(|| SinkKind::Device(settings.output_device.clone()));
+    let mut player = AudioPlayer::new(sink);
+    player.set_fade(Duration::from_millis(settings.fade_ms as u64));
+    player.set_crossfade(settings.crossfade);
+    player.set_replay_gain_mode(settings.replay_gain);
+    player.set_equalizer(settings.equalizer);
+
+    // The files listed, to tell what the scan and the watcher have both reported
+    let mut known_files: HashSet<PathBuf> = HashSet::new();
+    let mut loudness_scan: Option<LoudnessScan> = None;
+
+    // The last session picks up where it was left off
+    let session = Session::load();
+    player.set_volume(session.volume);
+    if !session.search.is_empty() {
+        app.search_input = session.search;
+        app.filter_list();
+    }
+    // Selected once the scan finds it, unless a key moves it first
+    let mut pending_selection = session.selected;
 
-    let mut app = App::new(&music_files);
-    let mut player = AudioPlayer::new();
+    if cli.shuffle {
+        player.set_shuffle(Shuffle::Bag);
+    }
 
-    let music_files_full_path: Vec<PathBuf> = music_files;
+    let mut resume = ResumeState::load();
+    let mut resume_saved = Instant::now();
+    // The queue as last written, which a queue given on the command line isn't yet
+    let mut queue_saved = player.queue().revision();
+    let mut play_library = false;
+    match requested {
+        // `play` without any paths plays the whole library, once it's scanned
+        Some(tracks) if tracks.is_empty() => play_library = true,
+        Some(tracks) => player.play_tracks(tracks, cli.start_paused),
+        None => {
+            player.restore_queue(SavedQueue::load().tracks, resume.queue_index);
+            queue_saved = player.queue().revision();
+            if let Some(path) = resume.last_track.clone()
+                && path.exists()
+            {
+                let position = Duration::from_secs_f64(resume.last_position);
+                let paused = session.paused || settings.start_paused || cli.start_paused;
+                player.play_song_with_position(Some(path), position, paused);
+            }
+        }
+    }
 
-    loop {
+    let result = loop {
+        player.advance_if_finished();
         player.update_position();
 
+        for event in player.poll_events() {
+            match event {
+                PlayerEvent::TrackStarted(ref path) => {
+                    app.current_song_tags = format_tags(&library, path);
+
+                    // A long recording started from the top offers to pick up where it was left
+                    app.resume_offer = resume
+                        .position(path)
+                        .filter(|&saved| saved > player.position() + Duration::from_secs(10))
+                        .map(|saved| (path.clone(), saved));
+                }
+                PlayerEvent::TrackEnded(ref path) => resume.forget(path),
+                PlayerEvent::Error(_) => app.set_status(event.to_string()),
+                PlayerEvent::DeviceLost => {
+                    // A device picked by hand falls back to the system default
+                    if player.output_device().is_some() {
+                        app.set_status(format!("{}, switching to system default", event));
+                        player.set_output_device(None);
+                    } else {
+                        app.set_status(event.to_string());
+                        player.stop();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if resume_saved.elapsed() >= Duration::from_secs(5) {
+            if player.is_playing() {
+                record_resume(&mut resume, &player);
+                let _ = resume.save();
+            }
+
+            let revision = player.queue().revision();
+            if revision != queue_saved {
+                save_queue(&player);
+                queue_saved = revision;
+            }
+            resume_saved = Instant::now();
+        }
+
+        let mut progress = Vec::new();
+
+        if let Some(scan) = &library_scan {
+            // Checked before collecting, so nothing found at the very end is missed
+            let finished = scan.is_finished();
+
+            let found: Vec<PathBuf> = scan
+                .new_files()
+                .into_iter()
+                .filter(|path| known_files.insert(path.clone()))
+                .collect();
+            app.add_music(&found);
+
+            if let Some(index) = pending_selection
+                .as_ref()
+                .and_then(|selected| app.filtered_list.iter().position(|path| path == selected))
+            {
+                app.list_state.select(Some(index));
+                pending_selection = None;
+            }
+
+            let (found, tagged, failed) = scan.progress();
+            progress.push(format!(
+                "Scanning library: {} found, {} tagged, {} errors",
+                found, tagged, failed
+            ));
+
+            if finished && let Some(scan) = library_scan.take() {
+                match scan.finish() {
+                    Ok(report) if report.failed > 0 => {
+                        app.set_status(format!("Couldn't read tags from {} files", report.failed));
+                    }
+                    Ok(_) => {}
+                    Err(e) => app.set_status(format!("Library scan failed: {}", e)),
+                }
+
+                if play_library {
+                    player.play_tracks(app.music_list.clone(), cli.start_paused);
+                    play_library = false;
+                }
+                pending_selection = None;
+            }
+        }
+
+        for change in watcher.changes() {
+            match change {
+                LibraryChange::Added(path) => {
+                    // The track playing may be the one whose tags were edited
+                    if player.current_path().as_ref() == Some(&path) {
+                        app.current_song_tags = format_tags(&library, &path);
+                    }
+                    if known_files.insert(path.clone()) {
+                        app.add_music(std::slice::from_ref(&path));
+                    }
+                }
+                LibraryChange::Removed(path) => {
+                    app.remove_music(&path);
+                    known_files.retain(|file| !file.starts_with(&path));
+                }
+                LibraryChange::Error(message) => app.set_status(message),
+            }
+        }
+
+        if let Some(scan) = &loudness_scan {
+            let (done, total) = scan.progress();
+            progress.push(format!("Analyzing loudness {}/{}", done, total));
+
+            if scan.is_finished() {
+                let failed = scan.failed();
+                if failed > 0 {
+                    app.set_status(format!("Loudness analysis failed for {} files", failed));
+                }
+                loudness_scan = None;
+            }
+        }
+
+        app.progress = (!progress.is_empty()).then(|| progress.join(" | "));
+
         let current_song_tags = app.current_song_tags.clone();
 
-        terminal.draw(|frame| ui(frame, &mut app, &current_song_tags, &player))?;
+        if let Err(e) = terminal.draw(|frame| ui(frame, &mut app, &current_song_tags, &player)) {
+            break Err(e.into());
+        }
 
-        if crossterm::event::poll(std::time::Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
-                match app.mode {
-                    AppMode::Normal => match (key.code, key.modifiers) {
-                        (KeyCode::Enter, KeyModifiers::NONE) => {
-                            if let Some(selected_filename) = app.get_selected_song() {
-                                let full_path = music_files_full_path.iter().find(|path| {
-                                    path.file_name()
-                                        .and_then(|name| name.to_str())
-                                        .is_some_and(|s| s == selected_filename)
-                                });
-
-                                if let Some(path) = full_path {
-                                    match get_music_tags(path.to_str().unwrap_or("")) {
-                                        Ok(tags) => {
-                                            app.current_song_tags = tags
-                                                .iter()
-                                                .map(|(key, value)| format!("{}: {}", key, value))
-                                                .collect::<Vec<String>>()
-                                                .join("\n");
-                                        }
-                                        Err(_) => {
-                                            app.current_song_tags =
-                                                "Unable to read tags".to_string();
-                                        }
-                                    }
-                                }
-                                let full_path_cloned = full_path.cloned();
-                                player.play_song(full_path_cloned);
-                            }
-                        }
-                        (KeyCode::Char('j'), KeyModifiers::NONE)
-                        | (KeyCode::Down, KeyModifiers::NONE) => {
-                            app.move_down();
-                        }
-                        (KeyCode::Char('k'), KeyModifiers::NONE)
-                        | (KeyCode::Up, KeyModifiers::NONE) => {
-                            app.move_up();
-                        }
-                        (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
-                            let area_height = terminal.size()?.height as usize;
-                            app.half_page_down(area_height);
-                        }
-                        (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
-                            let area_height = terminal.size()?.height as usize;
-                            app.half_page_up(area_height);
-                        }
-                        (KeyCode::Char('g'), KeyModifiers::NONE) => {
-                            app.list_state.select(Some(0));
+        let key = match read_key() {
+            Ok(key) => key,
+            Err(e) => break Err(e.into()),
+        };
+
+        if let Some(key) = key {
+            pending_selection = None;
+
+            match app.mode {
+                AppMode::Normal => match (key.code, key.modifiers) {
+                    (KeyCode::Enter, KeyModifiers::NONE) => {
+                        if let Some(path) = app.get_selected_song().cloned() {
+                            player.play_now(path);
                         }
-                        (KeyCode::Char('G'), KeyModifiers::SHIFT) => {
-                            app.list_state.select(Some(app.filtered_list.len() - 1));
+                    }
+                    (KeyCode::Char('a'), KeyModifiers::NONE) => {
+                        if let Some(path) = app.get_selected_song().cloned() {
+                            player.enqueue(path);
                         }
-                        (KeyCode::Char('/'), KeyModifiers::NONE) => {
-                            app.mode = AppMode::Search;
-                            app.search_input.clear();
+                    }
+                    (KeyCode::Char('A'), KeyModifiers::SHIFT) => {
+                        if let Some(path) = app.get_selected_song().cloned() {
+                            player.insert_next(path);
                         }
-                        (KeyCode::Char('p'), KeyModifiers::NONE) => {
-                            app.mode = AppMode::Play;
+                    }
+                    (KeyCode::Char('R'), KeyModifiers::SHIFT) => {
+                        if let Some((path, position)) = app.resume_offer.take() {
+                            player.play_song_with_position(Some(path), position, false);
                         }
-                        (KeyCode::Char('h'), KeyModifiers::NONE) => {
-                            app.mode = AppMode::Help;
+                    }
+                    (KeyCode::Char('j'), KeyModifiers::NONE)
+                    | (KeyCode::Down, KeyModifiers::NONE) => {
+                        app.move_down();
+                    }
+                    (KeyCode::Char('k'), KeyModifiers::NONE)
+                    | (KeyCode::Up, KeyModifiers::NONE) => {
+                        app.move_up();
+                    }
+                    (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
+                        let area_height = terminal.size().map_or(0, |size| size.height as usize);
+                        app.half_page_down(area_height);
+                    }
+                    (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
+                        let area_height = terminal.size().map_or(0, |size| size.height as usize);
+                        app.half_page_up(area_height);
+                    }
+                    (KeyCode::Char('g'), KeyModifiers::NONE) => {
+                        app.list_state.select(Some(0));
+                    }
+                    (KeyCode::Char('G'), KeyModifiers::SHIFT) => {
+                        app.list_state
+                            .select(app.filtered_list.len().checked_sub(1));
+                    }
+                    (KeyCode::Char('/'), KeyModifiers::NONE) => {
+                        app.mode = AppMode::Search;
+                        app.search_input.clear();
+                    }
+                    (KeyCode::Char('p'), KeyModifiers::NONE) => {
+                        app.mode = AppMode::Play;
+                    }
+                    (KeyCode::Char('Q'), KeyModifiers::SHIFT) => {
+                        app.mode = AppMode::Queue;
+                        app.queue_state
+                            .select(Some(player.queue().current_index().unwrap_or(0)));
+                    }
+                    // Shift+L fills the loudness cache, Ctrl+L also writes the tags
+                    (KeyCode::Char('L'), KeyModifiers::SHIFT) if loudness_scan.is_none() => {
+                        loudness_scan = Some(LoudnessScan::start(app.music_list.clone(), false));
+                    }
+                    (KeyCode::Char('l'), KeyModifiers::CONTROL) if loudness_scan.is_none() => {
+                        loudness_scan = Some(LoudnessScan::start(app.music_list.clone(), true));
+                    }
+                    (KeyCode::Char('e'), KeyModifiers::NONE) => {
+                        app.mode = AppMode::Equalizer;
+                    }
+                    (KeyCode::Char('o'), KeyModifiers::NONE) => {
+                        app.mode = AppMode::Devices;
+                        app.output_devices = list_output_devices();
+
+                        let current = player.output_device();
+                        let selected = app
+                            .output_devices
+                            .iter()
+                            .position(|device| Some(device) == current.as_ref())
+                            .map_or(0, |index| index + 1);
+                        app.device_state.select(Some(selected));
+                    }
+                    (KeyCode::Char('h'), KeyModifiers::NONE) => {
+                        app.mode = AppMode::Help;
+                    }
+                    (KeyCode::Char('q'), KeyModifiers::NONE)
+                    | (KeyCode::Esc, KeyModifiers::NONE) => break Ok(()),
+                    _ => {}
+                },
+                AppMode::Play => match (key.code, key.modifiers) {
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        app.mode = AppMode::Normal;
+                    }
+                    (KeyCode::Char(' '), KeyModifiers::NONE) => {
+                        player.toggle_pause();
+                    }
+                    (KeyCode::Right, KeyModifiers::NONE) => {
+                        player.seek_forward(5.0);
+                    }
+                    (KeyCode::Left, KeyModifiers::NONE) => {
+                        player.seek_backward(5.0);
+                    }
+                    (KeyCode::Char('n'), KeyModifiers::NONE) => {
+                        player.play_next();
+                    }
+                    (KeyCode::Char('N'), KeyModifiers::SHIFT) => {
+                        player.play_previous();
+                    }
+                    (KeyCode::Char('R'), KeyModifiers::SHIFT) => {
+                        if let Some((path, position)) = app.resume_offer.take() {
+                            player.play_song_with_position(Some(path), position, false);
                         }
-                        (KeyCode::Char('q'), KeyModifiers::NONE)
-                        | (KeyCode::Esc, KeyModifiers::NONE) => break,
-                        _ => {}
-                    },
-                    AppMode::Play => match (key.code, key.modifiers) {
+                    }
+                    (KeyCode::Char('+'), KeyModifiers::NONE) => {
+                        player.increase_volume(2.0);
+                    }
+                    (KeyCode::Char('-'), KeyModifiers::NONE) => {
+                        player.decrease_volume(2.0);
+                    }
+                    (KeyCode::Char('m'), KeyModifiers::NONE) => {
+                        player.toggle_mute();
+                    }
+                    (KeyCode::Char('b'), KeyModifiers::NONE) => {
+                        player.toggle_boost();
+                    }
+                    (KeyCode::Char('f'), KeyModifiers::NONE) => {
+                        const FADES: [u32; 5] = [0, 15, 30, 60, 120];
+                        let current = player.fade().as_millis() as u32;
+                        let fade_ms = FADES
+                            .into_iter()
+                            .find(|&fade| fade > current)
+                            .unwrap_or(FADES[0]);
+
+                        player.set_fade(Duration::from_millis(fade_ms as u64));
+                        settings.fade_ms = fade_ms;
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('c'), KeyModifiers::NONE) => {
+                        player.toggle_crossfade();
+                        settings.crossfade = player.crossfade_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('C'), KeyModifiers::SHIFT) => {
+                        player.cycle_crossfade_curve();
+                        settings.crossfade = player.crossfade_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('['), KeyModifiers::NONE) => {
+                        player.adjust_crossfade(-1.0);
+                        settings.crossfade = player.crossfade_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char(']'), KeyModifiers::NONE) => {
+                        player.adjust_crossfade(1.0);
+                        settings.crossfade = player.crossfade_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('a'), KeyModifiers::NONE) => {
+                        player.toggle_crossfade_same_album();
+                        settings.crossfade = player.crossfade_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('g'), KeyModifiers::NONE) => {
+                        player.cycle_replay_gain_mode();
+                        settings.replay_gain = player.replay_gain_mode();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('<'), KeyModifiers::NONE) => {
+                        player.adjust_speed(-0.25);
+                    }
+                    (KeyCode::Char('>'), KeyModifiers::NONE) => {
+                        player.adjust_speed(0.25);
+                    }
+                    (KeyCode::Char('{'), KeyModifiers::NONE) => {
+                        player.adjust_pitch(-1.0);
+                    }
+                    (KeyCode::Char('}'), KeyModifiers::NONE) => {
+                        player.adjust_pitch(1.0);
+                    }
+                    (KeyCode::Char('0'), KeyModifiers::NONE) => {
+                        player.reset_tempo();
+                    }
+                    (KeyCode::Char('l'), KeyModifiers::NONE) => {
+                        player.cycle_ab_loop();
+                    }
+                    (KeyCode::Char('r'), KeyModifiers::NONE) => {
+                        player.cycle_repeat();
+                    }
+                    (KeyCode::Char('s'), KeyModifiers::NONE) => {
+                        player.cycle_shuffle();
+                    }
+                    (KeyCode::Char('q'), KeyModifiers::NONE) => break Ok(()),
+                    _ => {}
+                },
+                AppMode::Queue => {
+                    let queue_len = player.queue().tracks().len();
+                    let selected = app.queue_state.selected().unwrap_or(0);
+
+                    match (key.code, key.modifiers) {
                         (KeyCode::Esc, KeyModifiers::NONE) => {
                             app.mode = AppMode::Normal;
                         }
-                        (KeyCode::Char(' '), KeyModifiers::NONE) => {
-                            player.toggle_pause();
+                        (KeyCode::Char('j'), KeyModifiers::NONE)
+                        | (KeyCode::Down, KeyModifiers::NONE) => {
+                            app.queue_move_down(queue_len);
                         }
-                        (KeyCode::Right, KeyModifiers::NONE) => {
-                            player.seek_forward(5.0);
+                        (KeyCode::Char('k'), KeyModifiers::NONE)
+                        | (KeyCode::Up, KeyModifiers::NONE) => {
+                            app.queue_move_up(queue_len);
                         }
-                        (KeyCode::Left, KeyModifiers::NONE) => {
-                            player.seek_backward(5.0);
+                        (KeyCode::Char('J'), KeyModifiers::SHIFT) if selected + 1 < queue_len => {
+                            player.move_in_queue(selected, selected + 1);
+                            app.queue_move_down(queue_len);
                         }
-                        (KeyCode::Char('+'), KeyModifiers::NONE) => {
-                            player.increase_volume(0.05);
+                        (KeyCode::Char('K'), KeyModifiers::SHIFT) if selected > 0 => {
+                            player.move_in_queue(selected, selected - 1);
+                            app.queue_move_up(queue_len);
                         }
-                        (KeyCode::Char('-'), KeyModifiers::NONE) => {
-                            player.decrease_volume(0.05);
+                        (KeyCode::Char('d'), KeyModifiers::NONE) => {
+                            player.remove_from_queue(selected);
+                            let queue_len = player.queue().tracks().len();
+                            app.queue_state
+                                .select(Some(selected.min(queue_len.saturating_sub(1))));
                         }
-                        (KeyCode::Char('q'), KeyModifiers::NONE) => break,
-                        _ => {}
-                    },
-                    AppMode::Search => match key.code {
-                        KeyCode::Char(c) => {
-                            app.search_input.push(c);
-                            app.filter_list();
+                        (KeyCode::Char('c'), KeyModifiers::NONE) => {
+                            player.clear_queue();
+                            app.queue_state.select(Some(0));
                         }
-                        KeyCode::Backspace => {
-                            app.search_input.pop();
-                            app.filter_list();
+                        (KeyCode::Enter, KeyModifiers::NONE) => {
+                            player.play_queue_index(selected);
                         }
-                        KeyCode::Esc => {
-                            app.mode = AppMode::Normal;
-                            app.search_input.clear();
-                            app.filter_list();
+                        (KeyCode::Char('r'), KeyModifiers::NONE) => {
+                            player.cycle_repeat();
                         }
-                        KeyCode::Enter => {
-                            app.mode = AppMode::Normal;
+                        (KeyCode::Char('s'), KeyModifiers::NONE) => {
+                            player.cycle_shuffle();
                         }
+                        (KeyCode::Char('q'), KeyModifiers::NONE) => break Ok(()),
                         _ => {}
-                    },
-                    AppMode::Help => match (key.code, key.modifiers) {
-                        (KeyCode::Esc, KeyModifiers::NONE) => {
-                            app.mode = AppMode::Normal;
-                        }
-                        (KeyCode::Char('q'), KeyModifiers::NONE) => break,
-                        _ => (),
-                    },
+                    }
                 }
+                AppMode::Equalizer => match (key.code, key.modifiers) {
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        app.mode = AppMode::Normal;
+                    }
+                    (KeyCode::Char('h'), KeyModifiers::NONE)
+                    | (KeyCode::Left, KeyModifiers::NONE) => {
+                        app.eq_band = app.eq_band.saturating_sub(1);
+                    }
+                    (KeyCode::Char('l'), KeyModifiers::NONE)
+                    | (KeyCode::Right, KeyModifiers::NONE) => {
+                        app.eq_band = (app.eq_band + 1).min(BANDS.len() - 1);
+                    }
+                    (KeyCode::Char('k'), KeyModifiers::NONE)
+                    | (KeyCode::Up, KeyModifiers::NONE) => {
+                        player.adjust_eq_band(app.eq_band, 1.0);
+                        settings.equalizer = player.equalizer_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('j'), KeyModifiers::NONE)
+                    | (KeyCode::Down, KeyModifiers::NONE) => {
+                        player.adjust_eq_band(app.eq_band, -1.0);
+                        settings.equalizer = player.equalizer_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char(' '), KeyModifiers::NONE) => {
+                        player.toggle_equalizer();
+                        settings.equalizer = player.equalizer_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('p'), KeyModifiers::NONE) => {
+                        player.cycle_eq_preset();
+                        settings.equalizer = player.equalizer_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('g'), KeyModifiers::NONE) => {
+                        player.toggle_eq_genre_presets();
+                        settings.equalizer = player.equalizer_settings();
+                        let _ = settings.save();
+                    }
+                    (KeyCode::Char('q'), KeyModifiers::NONE) => break Ok(()),
+                    _ => {}
+                },
+                AppMode::Devices => match (key.code, key.modifiers) {
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        app.mode = AppMode::Normal;
+                    }
+                    (KeyCode::Char('j'), KeyModifiers::NONE)
+                    | (KeyCode::Down, KeyModifiers::NONE) => {
+                        app.device_move_down();
+                    }
+                    (KeyCode::Char('k'), KeyModifiers::NONE)
+                    | (KeyCode::Up, KeyModifiers::NONE) => {
+                        app.device_move_up();
+                    }
+                    (KeyCode::Enter, KeyModifiers::NONE) => {
+                        let device = app.get_selected_device();
+                        player.set_output_device(device.clone());
+
+                        settings.output_device = device;
+                        let _ = settings.save();
+
+                        app.mode = AppMode::Normal;
+                    }
+                    (KeyCode::Char('q'), KeyModifiers::NONE) => break Ok(()),
+                    _ => {}
+                },
+                AppMode::Search => match key.code {
+                    KeyCode::Char(c) => {
+                        app.search_input.push(c);
+                        app.filter_list();
+                    }
+                    KeyCode::Backspace => {
+                        app.search_input.pop();
+                        app.filter_list();
+                    }
+                    KeyCode::Esc => {
+                        app.mode = AppMode::Normal;
+                        app.search_input.clear();
+                        app.filter_list();
+                    }
+                    KeyCode::Enter => {
+                        app.mode = AppMode::Normal;
+                    }
+                    _ => {}
+                },
+                AppMode::Help => match (key.code, key.modifiers) {
+                    (KeyCode::Esc, KeyModifiers::NONE) => {
+                        app.mode = AppMode::Normal;
+                    }
+                    (KeyCode::Char('q'), KeyModifiers::NONE) => break Ok(()),
+                    _ => (),
+                },
             }
         }
-    }
+    };
+
+    // Saved first, so the session survives even if the terminal can't be restored
+    save_session(&app, &player, &mut resume);
 
     disable_raw_mode()?;
     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
@@ -168,7 +635,78 @@ fn main() -> Result<(), Box<dyn Error>> {
     EXIT_NOW.store(true, std::sync::atomic::Ordering::SeqCst);
     player.stop();
 
-    Ok(())
+    result
+}
+
+fn read_key() -> io::Result<Option<KeyEvent>> {
+    if event::poll(Duration::from_millis(100))?
+        && let Event::Key(key) = event::read()?
+    {
+        return Ok(Some(key));
+    }
+
+    Ok(None)
+}
+
+// Notes where playback is, both for the track itself and for the next launch
+fn record_resume(resume: &mut ResumeState, player: &AudioPlayer) {
+    resume.queue_index = player.audible_queue_index();
+
+    // A track that already finished isn't brought back
+    let path = player.current_path().filter(|_| player.is_playing());
+    let Some(path) = path else {
+        resume.last_track = None;
+        return;
+    };
+
+    let position = player.position();
+    resume.remember(&path, position, player.duration());
+    resume.last_track = Some(path);
+    resume.last_position = position.as_secs_f64();
+}
+
+// Everything the next launch needs to pick up where this one leaves off
+fn save_session(app: &App, player: &AudioPlayer, resume: &mut ResumeState) {
+    let paused = player.is_paused();
+    record_resume(resume, player);
+    let _ = resume.save();
+    save_queue(player);
+
+    let session = Session {
+        volume: player.get_volume(),
+        search: app.search_input.clone(),
+        selected: app.get_selected_song().cloned(),
+        paused,
+    };
+    let _ = session.save();
+}
+
+fn save_queue(player: &AudioPlayer) {
+    let saved = SavedQueue {
+        tracks: player.queue().tracks().to_vec(),
+    };
+    let _ = saved.save();
+}
+
+fn format_clock(time: Duration) -> String {
+    format!("{:02}:{:02}", time.as_secs() / 60, time.as_secs() % 60)
+}
+
+// Files from outside the library aren't indexed, so they're read directly
+fn format_tags(library: &Library, path: &Path) -> String {
+    let tags = match library.track(path) {
+        Some(info) => Ok(info.tags()),
+        None => get_music_tags(path.to_str().unwrap_or("")),
+    };
+
+    match tags {
+        Ok(tags) if !tags.is_empty() => tags
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        _ => "Unable to read tags".to_string(),
+    }
 }
 
 fn ui(frame: &mut Frame, app: &mut App, music_info: &str, player: &AudioPlayer) {
@@ -201,6 +739,27 @@ fn ui(frame: &mut Frame, app: &mut App, music_info: &str, player: &AudioPlayer)
             .areas::<3>(frame.area())
             .to_vec()
         }
+        AppMode::Queue => Layout::vertical([
+            Constraint::Length(3), // Top bar
+            Constraint::Min(0),    // Queue
+            Constraint::Length(3), // Progress bar
+        ])
+        .areas::<3>(frame.area())
+        .to_vec(),
+        AppMode::Devices => Layout::vertical([
+            Constraint::Length(3), // Top bar
+            Constraint::Min(0),    // Device list
+            Constraint::Length(3), // Progress bar
+        ])
+        .areas::<3>(frame.area())
+        .to_vec(),
+        AppMode::Equalizer => Layout::vertical([
+            Constraint::Length(3), // Top bar
+            Constraint::Min(0),    // Equalizer bands
+            Constraint::Length(3), // Progress bar
+        ])
+        .areas::<3>(frame.area())
+        .to_vec(),
         AppMode::Help => Layout::vertical([
             Constraint::Length(3), // Top bar
             Constraint::Min(0),    // Help content
@@ -220,15 +779,45 @@ fn ui(frame: &mut Frame, app: &mut App, music_info: &str, player: &AudioPlayer)
     ])
     .areas::<2>(top_inner_area);
 
-    let title = Paragraph::new("Music Player");
+    // Messages stay up for a few seconds before the title comes back
+    let title = match &app.status {
+        Some((message, since)) if since.elapsed().as_secs() < 5 => {
+            Paragraph::new(message.clone()).style(Style::default().fg(Color::Red))
+        }
+        _ => {
+            let resume_offer = app.resume_offer.as_ref().map(|(path, position)| {
+                let name = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("");
+                format!("Press R to resume {} at {}", name, format_clock(*position))
+            });
+
+            Paragraph::new(
+                app.progress
+                    .clone()
+                    .or(resume_offer)
+                    .unwrap_or_else(|| "Music Player".to_string()),
+            )
+        }
+    };
     frame.render_widget(title, top_areas[0]);
 
-    let mode_text = match app.mode {
+    let mut mode_text = match app.mode {
         AppMode::Normal => "NORMAL".to_string(),
         AppMode::Search => "SEARCH".to_string(),
         AppMode::Play => "PLAY".to_string(),
+        AppMode::Queue => "QUEUE".to_string(),
+        AppMode::Devices => "DEVICES".to_string(),
+        AppMode::Equalizer => "EQUALIZER".to_string(),
         AppMode::Help => "HELP".to_string(),
     };
+    if player.shuffle() != Shuffle::Off {
+        mode_text = format!("SHUFFLE {} | {}", player.shuffle().name(), mode_text);
+    }
+    if player.repeat() != Repeat::Off {
+        mode_text = format!("REPEAT {} | {}", player.repeat().name(), mode_text);
+    }
     let mode_widget = Paragraph::new(mode_text).alignment(Alignment::Right);
     frame.render_widget(mode_widget, top_areas[1]);
 
@@ -298,6 +887,15 @@ fn ui(frame: &mut Frame, app: &mut App, music_info: &str, player: &AudioPlayer)
 
             render_music_content(frame, app, music_info, music_list_area, music_info_area);
         }
+        AppMode::Queue => {
+            render_queue(frame, app, player, main_layout[1]);
+        }
+        AppMode::Devices => {
+            render_devices(frame, app, player, main_layout[1]);
+        }
+        AppMode::Equalizer => {
+            render_equalizer(frame, app, player, main_layout[1]);
+        }
         AppMode::Help => {
             let help_text = render_help();
             let help_block = Block::default().title("Help").borders(Borders::ALL);
@@ -322,7 +920,15 @@ fn render_help() -> Paragraph<'static> {
     G          : Go to bottom of list
     /          : Enter search mode
     Enter      : Play selected song
+    a          : Add selected song to queue
+    A          : Play selected song next
     p          : Enter play mode
+    R          : Resume the offered track where it was left off
+    Q          : Open queue
+    o          : Choose output device
+    e          : Open equalizer
+    L          : Analyze loudness of files without ReplayGain tags
+    Ctrl+L     : Analyze loudness and write ReplayGain tags
     h          : Open help menu
     q, Esc     : Quit
 
@@ -336,8 +942,56 @@ fn render_help() -> Paragraph<'static> {
     Space      : Play/Pause
     Left       : Seek backward 5 seconds
     Right      : Seek forward 5 seconds
-    +          : Increase volume
-    -          : Decrease volume
+    n          : Next track in queue
+    N          : Previous track in queue
+    R          : Resume the offered track where it was left off
+    +          : Increase volume by 2 dB
+    -          : Decrease volume by 2 dB
+    m          : Mute/unmute
+    b          : Allow boosting up to +12 dB
+    f          : Cycle pause/seek/stop fade length
+    c          : Toggle crossfade
+    C          : Switch crossfade curve
+    [, ]       : Shorten/lengthen crossfade
+    a          : Toggle crossfade within an album
+    g          : Cycle ReplayGain off/track/album
+    <, >       : Slow down/speed up playback
+    {, }       : Lower/raise pitch by a semitone
+    0          : Reset speed and pitch
+    l          : Mark loop start A, then end B, then clear the loop
+    r          : Cycle repeat off/all/one
+    s          : Cycle shuffle off/random/no repeats
+    Esc        : Return to normal mode
+    q          : Quit
+
+    QUEUE MODE:
+    j, Down    : Move selection down
+    k, Up      : Move selection up
+    J          : Move track down
+    K          : Move track up
+    d          : Remove track
+    c          : Clear queue
+    Enter      : Play selected track
+    r          : Cycle repeat off/all/one
+    s          : Cycle shuffle off/random/no repeats
+    Esc        : Return to normal mode
+    q          : Quit
+
+    EQUALIZER MODE:
+    h, Left    : Select previous band
+    l, Right   : Select next band
+    k, Up      : Raise band by 1 dB
+    j, Down    : Lower band by 1 dB
+    Space      : Toggle equalizer
+    p          : Next preset
+    g          : Toggle presets by genre
+    Esc        : Return to normal mode
+    q          : Quit
+
+    DEVICES MODE:
+    j, Down    : Move selection down
+    k, Up      : Move selection up
+    Enter      : Switch to selected device
     Esc        : Return to normal mode
     q          : Quit
 
@@ -351,23 +1005,84 @@ fn render_help() -> Paragraph<'static> {
 
 fn render_play_controls(player: &AudioPlayer) -> Paragraph<'static> {
     let volume = player.get_volume();
+    let volume_text = if volume.muted {
+        "muted".to_string()
+    } else if volume.db <= Volume::MIN_DB {
+        "silent".to_string()
+    } else {
+        format!(
+            "{:+.0} dB{}",
+            volume.db,
+            if volume.boost { " (boost on)" } else { "" }
+        )
+    };
     let state = if player.is_paused() {
         "⏸ PAUSED"
     } else {
         "▶ PLAYING"
     };
+    let up_next = player
+        .queue()
+        .peek_next()
+        .and_then(|path| path.file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("-")
+        .to_string();
+    let crossfade = player.crossfade_settings();
+    let crossfade_text = if crossfade.enabled {
+        format!(
+            "{:.0}s {}{}",
+            crossfade.seconds,
+            crossfade.curve.name(),
+            if crossfade.skip_same_album {
+                ", not within albums"
+            } else {
+                ""
+            }
+        )
+    } else {
+        "off".to_string()
+    };
+
+    let tempo = player.tempo();
+    let ab_loop = match player.ab_loop() {
+        AbLoop::Off => "off".to_string(),
+        AbLoop::Start(_, a) => format!("{}-?", format_clock(a)),
+        AbLoop::Looping(_, a, b) => format!("{}-{}", format_clock(a), format_clock(b)),
+    };
 
     let controls_text = format!(
         "{}\n\n\
-         Volume: {:.0}%\n\n\
+         Volume: {}\n\
+         Speed: {:.2}x, pitch {:+.0} st\n\
+         A-B loop: {}\n\
+         Fades: {} ms\n\
+         Underruns: {}\n\
+         Crossfade: {}\n\
+         ReplayGain: {}\n\
+         Up next: {}\n\n\
          Controls:\n\
          Space: Play/Pause\n\
          ←/→: Seek backward/forward\n\
-         +/-: Volume up/down\n\
+         n/N: Next/previous track\n\
+         +/-/m/b: Volume up/down/mute/boost\n\
+         f: Fade length\n\
+         c/C/[/]/a: Crossfade on/curve/length/albums\n\
+         g: ReplayGain mode\n\
+         </>/{{/}}/0: Speed/pitch/reset\n\
+         l/r/s: A-B loop/repeat/shuffle\n\
          Esc: Return to Normal mode\n\
          q: Quit",
         state,
-        volume * 100.0
+        volume_text,
+        tempo.speed,
+        tempo.semitones,
+        ab_loop,
+        player.fade().as_millis(),
+        player.underruns(),
+        crossfade_text,
+        player.replay_gain_mode().name(),
+        up_next
     );
 
     Paragraph::new(controls_text)
@@ -375,6 +1090,113 @@ fn render_play_controls(player: &AudioPlayer) -> Paragraph<'static> {
         .wrap(Wrap { trim: true })
 }
 
+fn render_queue(frame: &mut Frame, app: &mut App, player: &AudioPlayer, area: Rect) {
+    let queue = player.queue();
+    let queue_block = Block::default()
+        .title(format!("Queue ({} tracks)", queue.tracks().len()))
+        .borders(Borders::ALL);
+
+    let items: Vec<ListItem> = queue
+        .tracks()
+        .iter()
+        .enumerate()
+        .map(|(index, path)| {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("Unknown");
+            let marker = if queue.current_index() == Some(index) {
+                "▶ "
+            } else {
+                "  "
+            };
+            ListItem::new(format!("{}{}", marker, name))
+        })
+        .collect();
+
+    let list = List::new(items)
+        .block(queue_block)
+        .highlight_style(Style::default().fg(Color::Yellow));
+
+    frame.render_stateful_widget(list, area, &mut app.queue_state);
+}
+
+fn render_devices(frame: &mut Frame, app: &mut App, player: &AudioPlayer, area: Rect) {
+    let current = player.output_device();
+    let devices_block = Block::default()
+        .title("Output Devices")
+        .borders(Borders::ALL);
+
+    let entries = std::iter::once((None, "System default".to_string())).chain(
+        app.output_devices
+            .iter()
+            .map(|device| (Some(device), format!("{} ({})", device.name, device.host))),
+    );
+
+    let items: Vec<ListItem> = entries
+        .map(|(device, label)| {
+            let marker = if device == current.as_ref() {
+                "▶ "
+            } else {
+                "  "
+            };
+            ListItem::new(format!("{}{}", marker, label))
+        })
+        .collect();
+
+    let list = List::new(items)
+        .block(devices_block)
+        .highlight_style(Style::default().fg(Color::Yellow));
+
+    frame.render_stateful_widget(list, area, &mut app.device_state);
+}
+
+fn render_equalizer(frame: &mut Frame, app: &App, player: &AudioPlayer, area: Rect) {
+    let eq = player.equalizer_settings();
+    let eq_block = Block::default()
+        .title(format!(
+            "Equalizer: {} | Preset: {} | Genre presets: {}",
+            if eq.enabled { "on" } else { "off" },
+            eq.preset.name(),
+            if eq.genre_presets { "on" } else { "off" }
+        ))
+        .borders(Borders::ALL);
+
+    // Bars start at -MAX_GAIN so cuts still show as short bars
+    let bars: Vec<Bar> = BANDS
+        .iter()
+        .zip(eq.gains)
+        .enumerate()
+        .map(|(index, (&frequency, gain))| {
+            let label = if frequency >= 1000.0 {
+                format!("{}k", frequency / 1000.0)
+            } else {
+                format!("{}", frequency)
+            };
+            let color = if index == app.eq_band {
+                Color::Yellow
+            } else {
+                Color::Cyan
+            };
+
+            Bar::default()
+                .value((gain + MAX_GAIN) as u64)
+                .text_value(format!("{:+}", gain))
+                .label(Line::from(label))
+                .style(Style::default().fg(color))
+        })
+        .collect();
+
+    let chart = BarChart::default()
+        .block(eq_block)
+        .data(BarGroup::default().bars(&bars))
+        .max((MAX_GAIN * 2.0) as u64)
+        .bar_width(5)
+        .bar_gap(2);
+
+    frame.render_widget(chart, area);
+}
+
 fn render_music_content(
     frame: &mut Frame,
     app: &mut App,
@@ -387,7 +1209,7 @@ fn render_music_content(
     let items: Vec<ListItem> = app
         .filtered_list
         .iter()
-        .map(|song| ListItem::new(song.as_str()))
+        .map(|song| ListItem::new(song_name(song)))
         .collect();
 
     let list = List::new(items)
@@ -1,59 +1,213 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use globset::GlobSet;
 use walkdir::WalkDir;
 use lofty::{
+    config::WriteOptions,
+    file::TaggedFile,
     prelude::*,
-    probe::Probe
+    probe::Probe,
+    tag::Tag
 };
 
-pub fn get_music(directory: &str) -> Vec<PathBuf> {
+use crate::config::LibraryRoot;
+
+// Scans every root and merges what they hold, so a file reachable from two
+// overlapping roots is only listed once
+pub fn get_music(roots: &[LibraryRoot]) -> Vec<PathBuf> {
+    music_files(roots).collect()
+}
+
+// The same walk as `get_music`, yielding files as they're found
+pub fn music_files(roots: &[LibraryRoot]) -> impl Iterator<Item = PathBuf> + '_ {
+    let mut seen = HashSet::new();
+
+    roots.iter()
+        .flat_map(scan_root)
+        .filter(move |path| seen.insert(path.clone()))
+}
+
+pub fn is_music(path: &Path) -> bool {
     let music_extensions = [
         "mp3", "flac", "wav", "aac", 
         "ogg", "m4a", "wma", "alac"
     ];
 
-    WalkDir::new(directory)
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| music_extensions.contains(&ext.to_lowercase().as_str()))
+}
+
+fn scan_root(root: &LibraryRoot) -> impl Iterator<Item = PathBuf> + '_ {
+    let excludes = root.exclude_set().unwrap_or_else(|_| GlobSet::empty());
+
+    WalkDir::new(&root.path)
+        .follow_links(root.follow_symlinks)
         .into_iter()
+        // Excluded directories aren't descended into at all
+        .filter_entry(move |entry| {
+            entry.path()
+                .strip_prefix(&root.path)
+                .map_or(true, |relative| !excludes.is_match(relative))
+        })
         .filter_map(|entry| entry.ok())
         .filter(|entry| {
-            entry.file_type().is_file()
-        })
-        .filter_map(|entry| {
-            let path = entry.path().to_path_buf();
-            
-            path.extension()
-                .and_then(|ext| ext.to_str())
-                .map(|ext| music_extensions.contains(&ext.to_lowercase().as_str()))
-                .filter(|&is_music| is_music)
-                .map(|_| path)
+            entry.file_type().is_file() && is_music(entry.path())
         })
-        .collect()
+        .map(|entry| entry.into_path())
 }
 
+// Everything the library keeps about a file, read in a single probe
+#[derive(Clone, Default)]
+pub struct TrackInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub duration: Option<Duration>,
+}
+
+impl TrackInfo {
+    pub fn tags(&self) -> Vec<(String, String)> {
+        let text = [
+            ("Title", &self.title),
+            ("Artist", &self.artist),
+            ("Album", &self.album),
+            ("Album Artist", &self.album_artist),
+            ("Genre", &self.genre),
+        ];
+        let numbers = [
+            ("Year", self.year),
+            ("Track", self.track),
+            ("Disc", self.disc),
+        ];
+
+        text.into_iter()
+            .filter_map(|(key, value)| Some((key.to_string(), value.clone()?)))
+            .chain(numbers.into_iter()
+                .filter_map(|(key, value)| Some((key.to_string(), value?.to_string())))
+            )
+            .collect()
+    }
+}
+
+pub fn get_track_info(path: &str) -> Result<TrackInfo, Box<dyn std::error::Error>> {
+    let tagged_file = Probe::open(path)?.read()?;
+    Ok(track_info(&tagged_file))
+}
 
 pub fn get_music_tags(path: &str) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
-    let tagged_file = Probe::open(path)
-        .expect("ERROR: Bad path")
-        .read()
-        .expect("ERROR: Failed to read file");
-
-    let tag = match tagged_file.primary_tag() {
-        Some(primary_tag) => primary_tag,
-        None => tagged_file.first_tag().expect("ERROR: No tags found"),
+    let tagged_file = Probe::open(path)?.read()?;
+
+    if tagged_file.primary_tag().is_none() && tagged_file.first_tag().is_none() {
+        return Err("No tags found".into());
+    }
+
+    Ok(track_info(&tagged_file).tags())
+}
+
+// Files without any tags still have a duration worth knowing
+fn track_info(tagged_file: &TaggedFile) -> TrackInfo {
+    let duration = Some(tagged_file.properties().duration()).filter(|duration| !duration.is_zero());
+
+    let Some(tag) = tagged_file.primary_tag().or_else(|| tagged_file.first_tag()) else {
+        return TrackInfo { duration, ..TrackInfo::default() };
     };
 
-    let mut tags = Vec::new();
+    TrackInfo {
+        title: tag.title().map(|title| title.to_string()),
+        artist: tag.artist().map(|artist| artist.to_string()),
+        album: tag.album().map(|album| album.to_string()),
+        album_artist: tag.get_string(&ItemKey::AlbumArtist).map(|artist| artist.to_string()),
+        genre: tag.genre().map(|genre| genre.to_string()),
+        year: tag.year(),
+        track: tag.track(),
+        disc: tag.disk(),
+        duration,
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+// What playback needs from a file's tags, all read when it's opened
+#[derive(Default)]
+pub struct PlaybackTags {
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub replay_gain: ReplayGain,
+}
+
+pub fn get_playback_tags(path: &str) -> PlaybackTags {
+    let Ok(tagged_file) = Probe::open(path).and_then(|probe| probe.read()) else {
+        return PlaybackTags::default();
+    };
 
-    if let Some(title) = tag.title() {
-        tags.push(("Title".to_string(), title.to_string()));
+    PlaybackTags {
+        album: find_tag(&tagged_file, ItemKey::AlbumTitle),
+        genre: find_tag(&tagged_file, ItemKey::Genre),
+        replay_gain: replay_gain(&tagged_file),
     }
+}
 
-    if let Some(artist) = tag.artist() {
-        tags.push(("Artist".to_string(), artist.to_string()));
-    } 
+pub fn get_replay_gain(path: &str) -> ReplayGain {
+    Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map(|tagged_file| replay_gain(&tagged_file))
+        .unwrap_or_default()
+}
 
-    if let Some(album) = tag.album() {
-        tags.push(("Album".to_string(), album.to_string()));
+// Any of the file's tags may carry a value, e.g. an APE tag next to ID3v2
+fn find_tag(tagged_file: &TaggedFile, key: ItemKey) -> Option<String> {
+    tagged_file.tags()
+        .iter()
+        .find_map(|tag| tag.get_string(&key))
+        .map(|value| value.to_string())
+}
+
+fn replay_gain(tagged_file: &TaggedFile) -> ReplayGain {
+    let find = |key: ItemKey| find_tag(tagged_file, key);
+
+    ReplayGain {
+        track_gain: find(ItemKey::ReplayGainTrackGain).and_then(|value| parse_gain(&value)),
+        track_peak: find(ItemKey::ReplayGainTrackPeak).and_then(|value| parse_gain(&value)),
+        album_gain: find(ItemKey::ReplayGainAlbumGain).and_then(|value| parse_gain(&value)),
+        album_peak: find(ItemKey::ReplayGainAlbumPeak).and_then(|value| parse_gain(&value)),
+    }
+}
+
+pub fn write_replay_gain(path: &str, gain: f32, peak: f32) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tagged_file = Probe::open(path)?.read()?;
+
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
     }
 
-    Ok(tags)
-} 
+    let tag = tagged_file.primary_tag_mut().ok_or("Unable to create a tag")?;
+    tag.insert_text(ItemKey::ReplayGainTrackGain, format!("{:.2} dB", gain));
+    tag.insert_text(ItemKey::ReplayGainTrackPeak, format!("{:.6}", peak));
+    tag.save_to_path(path, WriteOptions::default())?;
+
+    Ok(())
+}
+
+// Values look like "-6.48 dB" for gains and "0.988831" for peaks
+fn parse_gain(value: &str) -> Option<f32> {
+    value.split_whitespace()
+        .next()?
+        .trim_end_matches("dB")
+        .parse()
+        .ok()
+}
+
@@ -1,50 +1,267 @@
 use std::collections::VecDeque;
+use std::fmt;
 use std::fs::File;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, atomic::AtomicBool};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::SampleFormat;
+use rtrb::{Consumer, Producer, RingBuffer};
+use serde::{Deserialize, Serialize};
 use symphonia::core::{
-    audio::SampleBuffer, codecs::DecoderOptions, formats::FormatOptions, io::MediaSourceStream,
-    meta::MetadataOptions, probe::Hint,
+    audio::{Channels, SampleBuffer, SignalSpec},
+    codecs::{Decoder, DecoderOptions},
+    formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+    units::{Time, TimeBase},
 };
 
+use crate::equalizer::{EqSettings, Equalizer};
+use crate::loudness;
+use crate::music_manipulation::{ReplayGain, get_playback_tags};
+use crate::queue::{PlayQueue, Repeat, Shuffle};
+use crate::resample::Converter;
+use crate::sink::{OutputDevice, OutputFormat, Render, SinkKind};
+use crate::stretch::{Tempo, TimeStretch};
+
 pub static EXIT_NOW: AtomicBool = AtomicBool::new(false);
 
+// How long before the current track runs out the next one starts opening
+const PREOPEN: Duration = Duration::from_secs(5);
+
+pub enum PlayerEvent {
+    TrackStarted(PathBuf),
+    // Only sent for tracks that play through to their end
+    TrackEnded(PathBuf),
+    Error(String),
+    // Carries the count of underruns so far for the track
+    Underrun(u32),
+    DurationKnown(PathBuf, Duration),
+    DeviceLost,
+}
+
+impl fmt::Display for PlayerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlayerEvent::TrackStarted(path) => write!(f, "Playing {}", path.display()),
+            PlayerEvent::TrackEnded(path) => write!(f, "Finished {}", path.display()),
+            PlayerEvent::Error(message) => write!(f, "{}", message),
+            PlayerEvent::Underrun(count) => write!(f, "Audio underrun ({} so far)", count),
+            PlayerEvent::DurationKnown(path, duration) => write!(
+                f,
+                "{} is {:.1}s long",
+                path.display(),
+                duration.as_secs_f64()
+            ),
+            PlayerEvent::DeviceLost => write!(f, "Output device lost"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FadeCurve {
+    Linear,
+    EqualPower,
+}
+
+impl FadeCurve {
+    // Gains for the outgoing and incoming track at `t` (0..1) through the fade
+    fn gains(self, t: f32) -> (f32, f32) {
+        match self {
+            FadeCurve::Linear => (1.0 - t, t),
+            FadeCurve::EqualPower => {
+                let angle = t * std::f32::consts::FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FadeCurve::Linear => "linear",
+            FadeCurve::EqualPower => "equal power",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGainMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            ReplayGainMode::Off => "off",
+            ReplayGainMode::Track => "track",
+            ReplayGainMode::Album => "album",
+        }
+    }
+
+    // Linear gain for a track, held down where its peak would clip.
+    // Album mode falls back to the track values for untagged albums
+    fn gain(self, tags: &ReplayGain) -> f32 {
+        let (gain, peak) = match self {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Album if tags.album_gain.is_some() => {
+                (tags.album_gain, tags.album_peak)
+            }
+            _ => (tags.track_gain, tags.track_peak),
+        };
+
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+
+        let linear = 10f32.powf(gain / 20.0);
+        match peak {
+            Some(peak) if peak > 0.0 => linear.min(1.0 / peak),
+            _ => linear,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Volume {
+    pub db: f32,
+    pub muted: bool,
+    // Allows going above 0 dB, with a soft limiter catching the peaks
+    pub boost: bool,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            db: 0.0,
+            muted: false,
+            boost: false,
+        }
+    }
+}
+
+impl Volume {
+    // The bottom of the range is silence rather than a very quiet level
+    pub const MIN_DB: f32 = -60.0;
+    pub const MAX_BOOST_DB: f32 = 12.0;
+
+    fn gain(self) -> f32 {
+        if self.muted || self.db <= Self::MIN_DB {
+            0.0
+        } else {
+            10f32.powf(self.db / 20.0)
+        }
+    }
+
+    fn max_db(self) -> f32 {
+        if self.boost { Self::MAX_BOOST_DB } else { 0.0 }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum AbLoop {
+    Off,
+    // Point A is marked and B is still to come
+    Start(PathBuf, Duration),
+    Looping(PathBuf, Duration, Duration),
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrossfadeSettings {
+    pub enabled: bool,
+    pub seconds: f32,
+    pub curve: FadeCurve,
+    pub skip_same_album: bool,
+}
+
+impl Default for CrossfadeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seconds: 5.0,
+            curve: FadeCurve::EqualPower,
+            skip_same_album: true,
+        }
+    }
+}
+
 pub struct AudioPlayer {
-    _stream: Option<cpal::Stream>,
-    audio_buffer: Arc<Mutex<VecDeque<f32>>>,
     thread_handle: Option<thread::JoinHandle<()>>,
     should_stop: Arc<Mutex<bool>>,
     current_position: Arc<Mutex<Duration>>,
     total_duration: Arc<Mutex<Duration>>,
-    playback_started: Arc<Mutex<Option<Instant>>>,
+    frames_played: Arc<AtomicU64>,
+    sample_rate: Arc<Mutex<u32>>,
     is_playing: Arc<Mutex<bool>>,
-    is_paused: Arc<Mutex<bool>>,
+    is_paused: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+    fade_ms: Arc<AtomicU32>,
     current_song: Arc<Mutex<Option<String>>>,
-    current_volume: Arc<Mutex<f32>>,
+    volume: Volume,
+    // Linear gain the output callback ramps towards
+    current_volume: Arc<AtomicU32>,
     current_path: Arc<Mutex<Option<PathBuf>>>,
-    underruns: Arc<Mutex<u32>>,
+    underruns: Arc<AtomicU32>,
+    track_finished: Arc<Mutex<bool>>,
+    seek_request: Arc<Mutex<Option<Duration>>>,
+    // The latest seek, reported as the position until the decode thread has
+    // taken it, so seeks made in quick succession add up
+    pending_seek: Arc<Mutex<Option<Duration>>>,
+    sink: SinkKind,
+    events: Sender<PlayerEvent>,
+    events_rx: Receiver<PlayerEvent>,
+    crossfade: Arc<Mutex<CrossfadeSettings>>,
+    replay_gain: Arc<Mutex<ReplayGainMode>>,
+    equalizer: Arc<Mutex<EqSettings>>,
+    tempo: Arc<Mutex<Tempo>>,
+    ab_loop: Arc<Mutex<AbLoop>>,
+    segments: Arc<Mutex<VecDeque<Segment>>>,
+    queue: Arc<Mutex<PlayQueue>>,
 }
 
 impl AudioPlayer {
-    pub fn new() -> Self {
+    pub fn new(sink: SinkKind) -> Self {
+        let (events, events_rx) = mpsc::channel();
+
         AudioPlayer {
-            _stream: None,
-            audio_buffer: Arc::new(Mutex::new(VecDeque::new())),
             thread_handle: None,
             should_stop: Arc::new(Mutex::new(false)),
             current_position: Arc::new(Mutex::new(Duration::from_secs(0))),
             total_duration: Arc::new(Mutex::new(Duration::from_secs(0))),
-            playback_started: Arc::new(Mutex::new(None)),
+            frames_played: Arc::new(AtomicU64::new(0)),
+            sample_rate: Arc::new(Mutex::new(0)),
             is_playing: Arc::new(Mutex::new(false)),
-            is_paused: Arc::new(Mutex::new(false)),
+            is_paused: Arc::new(AtomicBool::new(false)),
+            stopping: Arc::new(AtomicBool::new(false)),
+            fade_ms: Arc::new(AtomicU32::new(30)),
             current_song: Arc::new(Mutex::new(None)),
-            current_volume: Arc::new(Mutex::new(1.0)),
+            volume: Volume::default(),
+            current_volume: Arc::new(AtomicU32::new(1f32.to_bits())),
             current_path: Arc::new(Mutex::new(None)),
-            underruns: Arc::new(Mutex::new(0)),
+            underruns: Arc::new(AtomicU32::new(0)),
+            track_finished: Arc::new(Mutex::new(false)),
+            seek_request: Arc::new(Mutex::new(None)),
+            pending_seek: Arc::new(Mutex::new(None)),
+            sink,
+            events,
+            events_rx,
+            crossfade: Arc::new(Mutex::new(CrossfadeSettings::default())),
+            replay_gain: Arc::new(Mutex::new(ReplayGainMode::Track)),
+            equalizer: Arc::new(Mutex::new(EqSettings::default())),
+            tempo: Arc::new(Mutex::new(Tempo::default())),
+            ab_loop: Arc::new(Mutex::new(AbLoop::Off)),
+            segments: Arc::new(Mutex::new(VecDeque::new())),
+            queue: Arc::new(Mutex::new(PlayQueue::new())),
         }
     }
 
@@ -59,228 +276,141 @@ impl AudioPlayer {
         if let Some(path) = file_path.clone() {
             *self.current_position.lock().unwrap() = position; // Start at the specified position
             *self.is_playing.lock().unwrap() = true;
-            *self.is_paused.lock().unwrap() = start_paused;
-            *self.playback_started.lock().unwrap() = Some(Instant::now() - position);
+            self.is_paused.store(start_paused, Ordering::Relaxed);
+            self.stopping.store(false, Ordering::Relaxed);
+            *self.total_duration.lock().unwrap() = Duration::from_secs(0);
+            self.frames_played.store(0, Ordering::Relaxed);
+            *self.sample_rate.lock().unwrap() = 0;
             *self.current_path.lock().unwrap() = file_path;
-            *self.underruns.lock().unwrap() = 0;
-
-            if let Some(filename) = path.file_name() {
-                if let Some(name) = filename.to_str() {
-                    *self.current_song.lock().unwrap() = Some(name.to_owned());
-                }
+            self.underruns.store(0, Ordering::Relaxed);
+            *self.track_finished.lock().unwrap() = false;
+            self.segments.lock().unwrap().clear();
+
+            if let Some(filename) = path.file_name()
+                && let Some(name) = filename.to_str()
+            {
+                *self.current_song.lock().unwrap() = Some(name.to_owned());
             }
 
             *self.should_stop.lock().unwrap() = false;
+            *self.seek_request.lock().unwrap() = None;
+            *self.pending_seek.lock().unwrap() = None;
 
             let should_stop_clone = Arc::clone(&self.should_stop);
             let path_clone = path.clone();
-            let total_duration_clone = Arc::clone(&self.total_duration);
+            let frames_played_clone = Arc::clone(&self.frames_played);
+            let sample_rate_clone = Arc::clone(&self.sample_rate);
+            let segments_clone = Arc::clone(&self.segments);
+            let queue_clone = Arc::clone(&self.queue);
             let is_playing_clone = Arc::clone(&self.is_playing);
             let volume_clone = Arc::clone(&self.current_volume);
-            let audio_buffer_clone = Arc::clone(&self.audio_buffer);
             let is_paused_clone = Arc::clone(&self.is_paused);
+            let stopping_clone = Arc::clone(&self.stopping);
+            let fade_ms_clone = Arc::clone(&self.fade_ms);
             let underruns_clone = Arc::clone(&self.underruns);
-            let seek_time = position.as_secs_f64();
+            let track_finished_clone = Arc::clone(&self.track_finished);
+            let seek_request_clone = Arc::clone(&self.seek_request);
+            let pending_seek_clone = Arc::clone(&self.pending_seek);
+            let crossfade_settings_clone = Arc::clone(&self.crossfade);
+            let replay_gain_clone = Arc::clone(&self.replay_gain);
+            let equalizer_clone = Arc::clone(&self.equalizer);
+            let tempo_clone = Arc::clone(&self.tempo);
+            let ab_loop_clone = Arc::clone(&self.ab_loop);
+            let sink_kind = self.sink.clone();
+            let events = self.events.clone();
 
             self.thread_handle = Some(thread::spawn(move || {
-                let file = match File::open(&path_clone) {
-                    Ok(file) => Box::new(file),
-                    Err(e) => {
-                        eprintln!("Error opening audio file: {}", e);
-                        *is_playing_clone.lock().unwrap() = false;
-                        return;
-                    }
-                };
-
-                let mss = MediaSourceStream::new(file, Default::default());
-
-                let hint = Hint::new();
-                let format_opts: FormatOptions = Default::default();
-                let metadata_opts: MetadataOptions = Default::default();
-                let decoder_opts: DecoderOptions = Default::default();
-
-                let probed = match symphonia::default::get_probe().format(
-                    &hint,
-                    mss,
-                    &format_opts,
-                    &metadata_opts,
-                ) {
-                    Ok(probed) => probed,
-                    Err(e) => {
-                        eprintln!("Error probing audio format: {}", e);
-                        *is_playing_clone.lock().unwrap() = false;
-                        return;
-                    }
+                // A track that can't be opened is skipped like it would be
+                // between tracks, and the position only applies to the one asked for
+                let Some(mut source) = open_queued_source(
+                    &queue_clone,
+                    Some(path_clone.clone()),
+                    &mut None,
+                    &events,
+                    |_| true,
+                ) else {
+                    *is_playing_clone.lock().unwrap() = false;
+                    return;
                 };
-
-                let mut format = probed.format;
-
-                let track = match format.default_track() {
-                    Some(track) => track,
-                    None => {
-                        eprintln!("No default track found in audio file");
-                        *is_playing_clone.lock().unwrap() = false;
-                        return;
-                    }
+                let position = if source.path == path_clone {
+                    position
+                } else {
+                    Duration::ZERO
                 };
 
-                let mut decoder = match symphonia::default::get_codecs()
-                    .make(&track.codec_params, &decoder_opts)
-                {
-                    Ok(decoder) => decoder,
+                let mut sink = match sink_kind.open() {
+                    Ok(sink) => sink,
                     Err(e) => {
-                        eprintln!("Error creating decoder: {}", e);
+                        let _ = events.send(PlayerEvent::Error(e));
                         *is_playing_clone.lock().unwrap() = false;
                         return;
                     }
                 };
 
-                if let Some(n_frames) = track.codec_params.n_frames {
-                    if let Some(rate) = track.codec_params.sample_rate {
-                        let total_secs = n_frames as f64 / rate as f64;
-                        *total_duration_clone.lock().unwrap() = Duration::from_secs_f64(total_secs);
-                    } else {
-                        *total_duration_clone.lock().unwrap() = Duration::from_secs(180); // default 3 minutes
-                    }
-                } else {
-                    *total_duration_clone.lock().unwrap() = Duration::from_secs(180);
-                }
-
-                let track_id = track.id;
-
-                if seek_time > 0.0 {
-                    let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
-                    let _channels = track
-                        .codec_params
-                        .channels
-                        .unwrap_or(symphonia::core::audio::Channels::FRONT_LEFT)
-                        .count();
-
-                    let frames_to_skip = (seek_time * sample_rate as f64) as usize;
-                    println!("Attempting to skip approximately {} frames", frames_to_skip);
-
-                    let mut frames_skipped = 0;
-                    while frames_skipped < frames_to_skip {
-                        match format.next_packet() {
-                            Ok(packet) => {
-                                if packet.track_id() != track_id {
-                                    continue;
-                                }
-
-                                if let Ok(audio_buf) = decoder.decode(&packet) {
-                                    frames_skipped += audio_buf.frames();
-                                    println!(
-                                        "Skipped {} frames, total: {}/{}",
-                                        audio_buf.frames(),
-                                        frames_skipped,
-                                        frames_to_skip
-                                    );
-                                }
-                            }
-                            Err(_) => break,
+                let output = sink.output_format(&source.spec);
+                let channel_count = output.channels;
+                source.set_output(output);
+
+                let mut stretch = TimeStretch::new(output.rate, channel_count);
+                stretch.set_tempo(*tempo_clone.lock().unwrap(), &mut Vec::new());
+
+                let mut start_offset = 0;
+                if !position.is_zero() {
+                    match source.seek(position) {
+                        Ok(frames) => start_offset = frames,
+                        Err(e) => {
+                            let _ = events.send(PlayerEvent::Error(format!(
+                                "Error seeking audio file: {}",
+                                e
+                            )));
+                            *is_playing_clone.lock().unwrap() = false;
+                            return;
                         }
                     }
                 }
 
-                let host = cpal::default_host();
-                let device = match host.default_output_device() {
-                    Some(device) => device,
-                    None => {
-                        eprintln!("No default audio output device available");
-                        *is_playing_clone.lock().unwrap() = false;
-                        return;
-                    }
-                };
-
-                let packet = match format.next_packet() {
-                    Ok(packet) => packet,
-                    Err(e) => {
-                        eprintln!("Error getting first audio packet: {}", e);
-                        *is_playing_clone.lock().unwrap() = false;
-                        return;
-                    }
-                };
-
-                let audio_buf = match decoder.decode(&packet) {
-                    Ok(buf) => buf,
-                    Err(e) => {
-                        eprintln!("Error decoding first audio packet: {}", e);
-                        *is_playing_clone.lock().unwrap() = false;
-                        return;
-                    }
-                };
-
-                let spec = *audio_buf.spec();
-                let sample_rate = cpal::SampleRate(spec.rate);
-                let channels = spec.channels.count() as u16;
-
-                let config = cpal::StreamConfig {
-                    channels,
-                    sample_rate,
-                    buffer_size: cpal::BufferSize::Default,
-                };
-
-                let mut sample_buf = SampleBuffer::<f32>::new(audio_buf.capacity() as u64, spec);
+                segments_clone.lock().unwrap().push_back(source.segment(
+                    0,
+                    start_offset,
+                    stretch.tempo(),
+                ));
 
-                sample_buf.copy_interleaved_ref(audio_buf);
+                *sample_rate_clone.lock().unwrap() = output.rate;
 
-                {
-                    let mut buffer = audio_buffer_clone.lock().unwrap();
-                    for &sample in sample_buf.samples().iter() {
-                        buffer.push_back(sample);
-                    }
-                }
-
-                let callback_is_paused = Arc::clone(&is_paused_clone);
-                let callback_audio_buffer = Arc::clone(&audio_buffer_clone);
-                let callback_volume = Arc::clone(&volume_clone);
-                let callback_underruns = Arc::clone(&underruns_clone);
-
-                let stream = match device.build_output_stream(
-                    &config,
-                    move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                        if *callback_is_paused.lock().unwrap() {
-                            for sample in data.iter_mut() {
-                                *sample = 0.0;
-                            }
-                            return;
-                        }
+                let max_buffer_size = output.rate as usize * channel_count * 2; // 2 sec
+                let (mut producer, consumer) = RingBuffer::<f32>::new(max_buffer_size);
 
-                        let mut buffer = callback_audio_buffer.lock().unwrap();
-                        let volume = *callback_volume.lock().unwrap();
+                let discard_until = Arc::new(AtomicU64::new(0));
 
-                        if buffer.len() < data.len() {
-                            let mut underruns = callback_underruns.lock().unwrap();
-                            *underruns += 1;
-
-                            for sample in data.iter_mut() {
-                                *sample = 0.0;
-                            }
-                        } else {
-                            for sample in data.iter_mut() {
-                                *sample = buffer.pop_front().unwrap_or(0.0) * volume;
-                            }
-                        }
-                    },
-                    |err| eprintln!("An error occurred on the output audio stream: {}", err),
-                    None,
-                ) {
-                    Ok(stream) => stream,
-                    Err(e) => {
-                        eprintln!("Error building audio output stream: {}", e);
-                        *is_playing_clone.lock().unwrap() = false;
-                        return;
-                    }
+                let shared = RenderShared {
+                    discard_until: Arc::clone(&discard_until),
+                    is_paused: Arc::clone(&is_paused_clone),
+                    stopping: stopping_clone,
+                    fade_ms: fade_ms_clone,
+                    volume: Arc::clone(&volume_clone),
+                    underruns: Arc::clone(&underruns_clone),
+                    frames_played: Arc::clone(&frames_played_clone),
                 };
+                // Picking up mid-track fades in like a resume would
+                let render = render_callback(consumer, output, shared, !position.is_zero());
 
-                if let Err(e) = stream.play() {
-                    eprintln!("Error starting audio playback: {}", e);
+                if let Err(e) = sink.start(output, render, events.clone()) {
+                    let _ = events.send(PlayerEvent::Error(e));
                     *is_playing_clone.lock().unwrap() = false;
                     return;
                 }
 
-                let max_buffer_size = spec.rate as usize * spec.channels.count() * 2; // 2 sec
+                let _ = events.send(PlayerEvent::TrackStarted(source.path.clone()));
+
                 let mut last_packet_decoded = false;
+                let mut frames_queued: u64 = 0;
+                let mut samples = Vec::new();
+                let mut unsent = Vec::new();
+                let mut equalizer = Equalizer::new(output.rate, channel_count);
+                let mut underruns_seen = 0;
+                let mut crossfade: Option<Crossfade> = None;
+                let mut crossfade_checked = false;
+                let mut preopen: Option<Preopen> = None;
 
                 loop {
                     if *should_stop_clone.lock().unwrap()
@@ -290,56 +420,238 @@ impl AudioPlayer {
                         break;
                     }
 
-                    if *is_paused_clone.lock().unwrap() {
+                    // Seeks are handled here, before the pause check, so seeking
+                    // while paused lands on the new position when resumed
+                    let seek_request = seek_request_clone.lock().unwrap().take();
+                    if let Some(target) = seek_request {
+                        // What never made it into the ring is dropped here; the
+                        // callback skips the rest
+                        frames_queued -= (unsent.len() / channel_count) as u64;
+                        unsent.clear();
+                        discard_until.store(frames_queued, Ordering::Release);
+
+                        let mut segments = segments_clone.lock().unwrap();
+                        drop_played_segments(
+                            &mut segments,
+                            frames_played_clone.load(Ordering::Relaxed),
+                            &events,
+                        );
+
+                        // The decoder may already be into the next track while the
+                        // previous one is still audible; the seek belongs to the latter
+                        let audible = segments.front().map(|segment| segment.path.clone());
+
+                        if let Some(fade) = crossfade.take() {
+                            if audible.as_ref() == Some(&fade.next.path) {
+                                source = fade.next;
+                            } else {
+                                queue_clone.lock().unwrap().previous();
+                            }
+                        }
+
+                        if let Some(audible) = audible
+                            && audible != source.path
+                            && let Ok(previous) = Source::open(&audible, &events)
+                        {
+                            source = previous;
+                            queue_clone.lock().unwrap().previous();
+                        }
+
+                        crossfade_checked = false;
+                        segments.clear();
+                        // Speed changes come in as seeks to the audible position, so
+                        // they're heard right away rather than after the buffer drains
+                        stretch.reset();
+                        stretch.set_tempo(*tempo_clone.lock().unwrap(), &mut Vec::new());
+                        match source.seek(target) {
+                            Ok(offset) => {
+                                segments.push_back(source.segment(
+                                    frames_queued,
+                                    offset,
+                                    stretch.tempo(),
+                                ));
+                                last_packet_decoded = false;
+                            }
+                            // Seeking past the end of the stream just ends the track
+                            Err(_) => last_packet_decoded = true,
+                        }
+
+                        // The segment now agrees with the target, unless another
+                        // seek has come in since
+                        let mut pending_seek = pending_seek_clone.lock().unwrap();
+                        if seek_request_clone.lock().unwrap().is_none() {
+                            *pending_seek = None;
+                        }
+                    }
+
+                    if is_paused_clone.load(Ordering::Relaxed) {
                         thread::sleep(Duration::from_millis(10));
                         continue;
                     }
 
-                    let current_buffer_len = {
-                        let buffer = audio_buffer_clone.lock().unwrap();
-                        buffer.len()
-                    };
+                    // The tail of the last track always ends on a short period,
+                    // which isn't worth reporting
+                    let underruns = underruns_clone.load(Ordering::Relaxed);
+                    if underruns > underruns_seen && !last_packet_decoded {
+                        let _ = events.send(PlayerEvent::Underrun(underruns));
+                    }
+                    underruns_seen = underruns;
 
-                    if current_buffer_len > max_buffer_size - (spec.channels.count() * 1024) {
+                    // Nothing more is decoded until the last packet has fit in the ring
+                    push_samples(&mut producer, &mut unsent);
+                    if !unsent.is_empty() {
                         thread::sleep(Duration::from_millis(10));
                         continue;
                     }
 
                     if !last_packet_decoded {
-                        match format.next_packet() {
-                            Ok(packet) => {
-                                if packet.track_id() != track_id {
-                                    continue;
-                                }
-
-                                match decoder.decode(&packet) {
-                                    Ok(audio_buf) => {
-                                        sample_buf.copy_interleaved_ref(audio_buf);
-
-                                        {
-                                            let mut buffer = audio_buffer_clone.lock().unwrap();
-                                            for &sample in sample_buf.samples().iter() {
-                                                buffer.push_back(sample);
-                                            }
-                                        }
-                                    }
-                                    Err(symphonia::core::errors::Error::DecodeError(_)) => (),
-                                    Err(_) => {
-                                        last_packet_decoded = true;
-                                    }
-                                }
+                        samples.clear();
+
+                        let settings = *crossfade_settings_clone.lock().unwrap();
+                        // Remaining frames are counted in track time, so at higher speeds
+                        // the fade starts earlier in the track to last as long
+                        let fade_frames =
+                            (settings.seconds * output.rate as f32 * stretch.tempo().speed) as u64;
+
+                        // Once the outgoing track is within the fade length of its end,
+                        // the next one is opened and mixed in underneath it
+                        let ab_loop = match &*ab_loop_clone.lock().unwrap() {
+                            AbLoop::Looping(path, a, b) if *path == source.path => Some((*a, *b)),
+                            _ => None,
+                        };
+
+                        // The next track starts opening on the side a little before it's
+                        // needed, either to fade in or to follow on
+                        let mut lookahead = (PREOPEN.as_secs_f32() * output.rate as f32) as u64;
+                        if settings.enabled {
+                            lookahead += fade_frames;
+                        }
+                        if preopen.is_none()
+                            && crossfade.is_none()
+                            && source
+                                .remaining_frames()
+                                .is_some_and(|remaining| remaining <= lookahead)
+                        {
+                            let next = queue_clone.lock().unwrap().peek_next().cloned();
+                            preopen = next.map(|next| Preopen::start(next, &events));
+                        }
+
+                        if settings.enabled
+                            && !crossfade_checked
+                            && ab_loop.is_none()
+                            && source
+                                .remaining_frames()
+                                .is_some_and(|remaining| remaining <= fade_frames)
+                        {
+                            crossfade_checked = true;
+
+                            let skip_album = settings.skip_same_album;
+                            if let Some(mut next) = open_queued_source(
+                                &queue_clone,
+                                None,
+                                &mut preopen,
+                                &events,
+                                |next| !(skip_album && same_album(&source, next)),
+                            ) {
+                                next.set_output(source.output);
+                                let length = source.remaining_frames().unwrap_or(0).max(1);
+                                segments_clone.lock().unwrap().push_back(next.segment(
+                                    frames_queued,
+                                    0,
+                                    stretch.tempo(),
+                                ));
+                                crossfade = Some(Crossfade::new(next, length, settings.curve));
+                            }
+                        }
+
+                        // Checked every packet so a mode change is heard within the buffer length
+                        let gain_mode = *replay_gain_clone.lock().unwrap();
+                        source.set_gain(gain_mode);
+                        if let Some(fade) = &mut crossfade {
+                            fade.next.set_gain(gain_mode);
+                        }
+
+                        // A crossfade keeps the outgoing track's genre preset until it's over
+                        let eq = *equalizer_clone.lock().unwrap();
+                        equalizer.set_gains(eq.gains_for(source.genre.as_deref()));
+
+                        if source.read_packet(&mut samples) {
+                            // Reaching B cuts the packet off there and carries straight
+                            // on from A, with no gap in the stream
+                            let mut looped_to = None;
+                            if crossfade.is_none()
+                                && let Some((a, b)) = ab_loop
+                                && let Some(past) = source.frames_past(b)
+                            {
+                                let frames = (samples.len() / channel_count).saturating_sub(past);
+                                samples.truncate(frames * channel_count);
+                                looped_to = source.seek(a).ok();
+                            }
+
+                            if let Some(fade) = &mut crossfade {
+                                fade.mix(&mut samples, channel_count);
                             }
-                            Err(_) => {
-                                last_packet_decoded = true;
+
+                            stretch.process(&mut samples);
+                            equalizer.process(&mut samples);
+                            unsent.extend_from_slice(&samples);
+                            frames_queued += (samples.len() / channel_count) as u64;
+
+                            if let Some(offset) = looped_to {
+                                let mut segment =
+                                    source.segment(frames_queued, offset, stretch.tempo());
+                                segment.looped = true;
+                                segments_clone.lock().unwrap().push_back(segment);
                             }
+                        } else if let Some(fade) = crossfade.take() {
+                            // The outgoing track is done; whatever the incoming one
+                            // decoded ahead of the mix is played as is
+                            let (next, mut leftover) = fade.finish();
+                            stretch.process(&mut leftover);
+                            equalizer.process(&mut leftover);
+                            unsent.extend_from_slice(&leftover);
+                            frames_queued += (leftover.len() / channel_count) as u64;
+
+                            source = next;
+                            crossfade_checked = false;
+                        } else if let Some(mut next) =
+                            open_queued_source(&queue_clone, None, &mut preopen, &events, |_| true)
+                        {
+                            // The next track is converted to this stream's format,
+                            // so it keeps feeding the same stream
+                            next.set_output(source.output);
+                            segments_clone.lock().unwrap().push_back(next.segment(
+                                frames_queued,
+                                0,
+                                stretch.tempo(),
+                            ));
+                            source = next;
+                            crossfade_checked = false;
+                        } else {
+                            // The stretch holds back a little audio it still has to play out
+                            let mut tail = Vec::new();
+                            stretch.flush(&mut tail);
+                            equalizer.process(&mut tail);
+                            unsent.extend_from_slice(&tail);
+                            frames_queued += (tail.len() / channel_count) as u64;
+
+                            last_packet_decoded = true;
                         }
                     } else {
-                        let buffer_empty = {
-                            let buffer = audio_buffer_clone.lock().unwrap();
-                            buffer.is_empty()
-                        };
+                        let buffer_empty = producer.slots() == producer.buffer().capacity();
 
                         if buffer_empty {
+                            let mut segments = segments_clone.lock().unwrap();
+                            drop_played_segments(
+                                &mut segments,
+                                frames_played_clone.load(Ordering::Relaxed),
+                                &events,
+                            );
+                            if let Some(segment) = segments.front() {
+                                let _ = events.send(PlayerEvent::TrackEnded(segment.path.clone()));
+                            }
+
+                            *track_finished_clone.lock().unwrap() = true;
                             *is_playing_clone.lock().unwrap() = false;
                             break;
                         }
@@ -352,12 +664,15 @@ impl AudioPlayer {
     }
 
     pub fn stop(&mut self) {
+        // Gives the output time to fade out before the stream goes away
+        if self.is_playing() && !self.is_paused() {
+            self.stopping.store(true, Ordering::Relaxed);
+            thread::sleep(self.fade() + Duration::from_millis(10));
+        }
+
         *self.should_stop.lock().unwrap() = true;
         *self.is_playing.lock().unwrap() = false;
-        *self.is_paused.lock().unwrap() = false;
-
-        let mut buffer = self.audio_buffer.lock().unwrap();
-        buffer.clear();
+        self.is_paused.store(false, Ordering::Relaxed);
 
         if let Some(handle) = self.thread_handle.take() {
             let timeout = Duration::from_millis(200);
@@ -372,35 +687,215 @@ impl AudioPlayer {
 
             match handle.join() {
                 Ok(_) => (),
-                Err(e) => eprintln!("Error joining audio thread: {:?}", e),
+                Err(_) => {
+                    let _ = self
+                        .events
+                        .send(PlayerEvent::Error("Audio thread panicked".to_string()));
+                }
             }
         }
 
-        self._stream = None;
+        // Tracks the decoder had already moved on to but never got to play
+        // go back to being upcoming in the queue
+        let unplayed = self.unplayed_tracks();
+        let mut queue = self.queue.lock().unwrap();
+        for _ in 0..unplayed {
+            queue.previous();
+        }
+        self.segments.lock().unwrap().clear();
+    }
+
+    // Tracks the decoder has moved the queue on to that aren't audible yet
+    fn unplayed_tracks(&self) -> usize {
+        let frames_played = self.frames_played.load(Ordering::Relaxed);
+        let mut segments = self.segments.lock().unwrap();
+        drop_played_segments(&mut segments, frames_played, &self.events);
+
+        segments
+            .iter()
+            .skip(1)
+            .filter(|segment| !segment.looped)
+            .count()
+    }
+
+    // The queue entry being heard, which lags the queue's own cursor while
+    // the decoder is working ahead into the next track
+    pub fn audible_queue_index(&self) -> Option<usize> {
+        let unplayed = self.unplayed_tracks();
+        self.queue.lock().unwrap().index_back(unplayed)
     }
 
     pub fn play_song(&mut self, file_path: Option<PathBuf>) {
         self.play_song_with_position(file_path, Duration::from_secs(0), false);
     }
 
-    pub fn update_position(&self) {
-        let mut position = self.current_position.lock().unwrap();
-        let is_playing = *self.is_playing.lock().unwrap();
-        let is_paused = *self.is_paused.lock().unwrap();
+    pub fn play_now(&mut self, file_path: PathBuf) {
+        self.stop();
 
-        if is_playing && !is_paused {
-            let total = *self.total_duration.lock().unwrap();
+        let path = self.queue.lock().unwrap().play_now(file_path);
+        self.play_song(Some(path));
+    }
 
-            if let Some(start_time) = *self.playback_started.lock().unwrap() {
-                *position = start_time.elapsed();
+    pub fn play_queue_index(&mut self, index: usize) {
+        self.stop();
 
-                if *position > total {
-                    *position = total;
-                }
+        let path = self.queue.lock().unwrap().jump_to(index);
+        if let Some(path) = path {
+            self.play_song(Some(path));
+        }
+    }
+
+    pub fn play_next(&mut self) {
+        self.stop();
+
+        let path = self.queue.lock().unwrap().skip();
+        if let Some(path) = path {
+            self.play_song(Some(path));
+        }
+    }
+
+    pub fn play_previous(&mut self) {
+        self.stop();
+
+        let path = self.queue.lock().unwrap().previous();
+        if let Some(path) = path {
+            self.play_song(Some(path));
+        }
+    }
+
+    // Starts the next queued track once the decode thread has drained the buffer
+    pub fn advance_if_finished(&mut self) {
+        let finished = std::mem::take(&mut *self.track_finished.lock().unwrap());
+
+        if finished {
+            self.stop();
+
+            let path = self.queue.lock().unwrap().next();
+            if let Some(path) = path {
+                self.play_song(Some(path));
             }
         }
     }
 
+    pub fn enqueue(&mut self, file_path: PathBuf) {
+        self.queue.lock().unwrap().enqueue(file_path);
+    }
+
+    pub fn insert_next(&mut self, file_path: PathBuf) {
+        self.queue.lock().unwrap().insert_next(file_path);
+    }
+
+    pub fn remove_from_queue(&mut self, index: usize) {
+        self.queue.lock().unwrap().remove(index);
+    }
+
+    pub fn move_in_queue(&mut self, from: usize, to: usize) {
+        self.queue.lock().unwrap().move_track(from, to);
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.queue.lock().unwrap().clear();
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.queue.lock().unwrap().repeat()
+    }
+
+    pub fn cycle_repeat(&mut self) {
+        self.queue.lock().unwrap().cycle_repeat();
+    }
+
+    pub fn shuffle(&self) -> Shuffle {
+        self.queue.lock().unwrap().shuffle()
+    }
+
+    pub fn cycle_shuffle(&mut self) {
+        self.queue.lock().unwrap().cycle_shuffle();
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: Shuffle) {
+        self.queue.lock().unwrap().set_shuffle(shuffle);
+    }
+
+    // Replaces the queue and starts on its first track, or a random one when shuffling
+    pub fn play_tracks(&mut self, tracks: Vec<PathBuf>, start_paused: bool) {
+        self.restore_queue(tracks, None);
+
+        let path = self.queue.lock().unwrap().skip();
+        self.play_song_with_position(path, Duration::from_secs(0), start_paused);
+    }
+
+    // Puts back a saved queue without starting playback
+    pub fn restore_queue(&mut self, tracks: Vec<PathBuf>, index: Option<usize>) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.clear();
+        for track in tracks {
+            queue.enqueue(track);
+        }
+        if let Some(index) = index {
+            queue.jump_to(index);
+        }
+    }
+
+    pub fn queue(&self) -> MutexGuard<'_, PlayQueue> {
+        self.queue.lock().unwrap()
+    }
+
+    pub fn poll_events(&self) -> Vec<PlayerEvent> {
+        self.events_rx.try_iter().collect()
+    }
+
+    // Position comes from the frames the output callback has actually played,
+    // measured from the start of whichever track segment is audible right now
+    // and scaled by the speed that segment was stretched to
+    pub fn update_position(&self) {
+        let rate = *self.sample_rate.lock().unwrap();
+        if rate == 0 {
+            return;
+        }
+
+        let frames_played = self.frames_played.load(Ordering::Relaxed);
+        let mut segments = self.segments.lock().unwrap();
+        drop_played_segments(&mut segments, frames_played, &self.events);
+
+        let Some(segment) = segments.front() else {
+            return;
+        };
+
+        let elapsed = frames_played.saturating_sub(segment.start_frame) as f64 * segment.speed;
+        let frames = segment.start_offset + elapsed as u64;
+        let total = segment.duration.lock().unwrap().unwrap_or_default();
+
+        let mut position = Duration::from_secs_f64(frames as f64 / rate as f64);
+        if !total.is_zero() {
+            position = position.min(total);
+        }
+        if let Some(target) = *self.pending_seek.lock().unwrap() {
+            position = target;
+        }
+
+        *self.current_position.lock().unwrap() = position;
+        *self.total_duration.lock().unwrap() = total;
+
+        let mut current_path = self.current_path.lock().unwrap();
+        if current_path.as_ref() != Some(&segment.path) {
+            *current_path = Some(segment.path.clone());
+            *self.current_song.lock().unwrap() = segment
+                .path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.to_owned());
+        }
+    }
+
+    pub fn position(&self) -> Duration {
+        *self.current_position.lock().unwrap()
+    }
+
+    pub fn duration(&self) -> Duration {
+        *self.total_duration.lock().unwrap()
+    }
+
     pub fn get_progress(&self) -> f32 {
         let position = *self.current_position.lock().unwrap();
         let total = *self.total_duration.lock().unwrap();
@@ -424,6 +919,10 @@ impl AudioPlayer {
         let total_min = total_secs / 60;
         let total_sec = total_secs % 60;
 
+        if total.is_zero() {
+            return format!("{:02}:{:02}/--:--", position_min, position_sec);
+        }
+
         format!(
             "{:02}:{:02}/{:02}:{:02}",
             position_min, position_sec, total_min, total_sec
@@ -438,23 +937,21 @@ impl AudioPlayer {
         self.current_song.lock().unwrap().clone()
     }
 
-    pub fn toggle_pause(&mut self) {
-        let mut is_paused = self.is_paused.lock().unwrap();
-
-        if *is_paused {
-            *is_paused = false;
+    pub fn current_path(&self) -> Option<PathBuf> {
+        self.current_path.lock().unwrap().clone()
+    }
 
-            // Update playback_started to account for paused time
-            let mut playback_started = self.playback_started.lock().unwrap();
-            let current_position = *self.current_position.lock().unwrap();
-            *playback_started = Some(Instant::now() - current_position);
-        } else {
-            *is_paused = true;
-        }
+    pub fn toggle_pause(&mut self) {
+        self.is_paused.fetch_xor(true, Ordering::Relaxed);
     }
 
     pub fn is_paused(&self) -> bool {
-        *self.is_paused.lock().unwrap()
+        self.is_paused.load(Ordering::Relaxed)
+    }
+
+    // Output periods the decoder didn't fill in time since the track started
+    pub fn underruns(&self) -> u32 {
+        self.underruns.load(Ordering::Relaxed)
     }
 
     pub fn seek_forward(&mut self, seconds: f32) {
@@ -464,149 +961,893 @@ impl AudioPlayer {
 
         self.update_position();
 
-        let current_position;
-        let total_duration;
-        let current_path;
-        let was_paused;
+        let current_position = *self.current_position.lock().unwrap();
+        let total_duration = *self.total_duration.lock().unwrap();
 
-        {
-            current_position = *self.current_position.lock().unwrap();
-            total_duration = *self.total_duration.lock().unwrap();
-            current_path = self.current_path.lock().unwrap().clone();
-            was_paused = self.is_paused();
+        // Until the length is known there's nothing to clamp to
+        let mut target_position = current_position + Duration::from_secs_f32(seconds);
+        if !total_duration.is_zero() {
+            target_position = target_position.min(total_duration);
         }
+        self.seek_to(target_position);
+    }
 
-        if current_path.is_none() {
+    pub fn seek_backward(&mut self, seconds: f32) {
+        if !self.is_playing() {
             return;
         }
 
-        let target_position =
-            (current_position + Duration::from_secs_f32(seconds)).min(total_duration);
+        self.update_position();
+
+        let current_position = *self.current_position.lock().unwrap();
 
-        println!(
-            "Seeking forward from {:?} to {:?}",
-            current_position, target_position
-        );
+        let target_position = current_position.saturating_sub(Duration::from_secs_f32(seconds));
+        self.seek_to(target_position);
+    }
 
-        {
-            let mut should_stop = self.should_stop.lock().unwrap();
-            *should_stop = true;
+    // Hands the seek to the running decode thread instead of restarting it
+    pub fn seek_to(&mut self, position: Duration) {
+        if !self.is_playing() {
+            return;
         }
 
-        thread::sleep(Duration::from_millis(50));
+        // Both are set together, so the decode thread never clears a newer target
+        let mut pending_seek = self.pending_seek.lock().unwrap();
+        *pending_seek = Some(position);
+        *self.seek_request.lock().unwrap() = Some(position);
+        *self.current_position.lock().unwrap() = position;
+    }
 
-        {
-            let mut buffer = self.audio_buffer.lock().unwrap();
-            buffer.clear();
-        }
+    // Volume moves in dB steps, which sound even across the range
+    pub fn increase_volume(&mut self, db: f32) {
+        let volume = self.volume;
+        self.set_volume(Volume {
+            db: (volume.db + db).min(volume.max_db()),
+            muted: false,
+            ..volume
+        });
+    }
 
-        let thread_handle = self.thread_handle.take();
-        if let Some(handle) = thread_handle {
-            std::mem::forget(handle);
-        }
+    pub fn decrease_volume(&mut self, db: f32) {
+        let volume = self.volume;
+        self.set_volume(Volume {
+            db: (volume.db - db).max(Volume::MIN_DB),
+            muted: false,
+            ..volume
+        });
+    }
 
-        {
-            let mut is_playing = self.is_playing.lock().unwrap();
-            *is_playing = false;
-        }
+    pub fn fade(&self) -> Duration {
+        Duration::from_millis(self.fade_ms.load(Ordering::Relaxed) as u64)
+    }
 
-        thread::sleep(Duration::from_millis(100));
+    pub fn set_fade(&mut self, fade: Duration) {
+        self.fade_ms
+            .store(fade.as_millis() as u32, Ordering::Relaxed);
+    }
 
-        let path = current_path.unwrap();
-        self.play_song_with_position(Some(path), target_position, was_paused);
+    pub fn toggle_mute(&mut self) {
+        let volume = self.volume;
+        self.set_volume(Volume {
+            muted: !volume.muted,
+            ..volume
+        });
     }
 
-    pub fn seek_backward(&mut self, seconds: f32) {
+    // Turning the boost off brings anything above 0 dB back down
+    pub fn toggle_boost(&mut self) {
+        let volume = self.volume;
+        self.set_volume(Volume {
+            db: volume.db.min(0.0),
+            boost: !volume.boost,
+            ..volume
+        });
+    }
+
+    pub fn set_volume(&mut self, volume: Volume) {
+        self.volume = volume;
+        self.current_volume
+            .store(volume.gain().to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get_volume(&self) -> Volume {
+        self.volume
+    }
+
+    pub fn output_device(&self) -> Option<OutputDevice> {
+        match &self.sink {
+            SinkKind::Device(device) => device.clone(),
+            _ => None,
+        }
+    }
+
+    pub fn set_output_device(&mut self, device: Option<OutputDevice>) {
+        self.set_sink(SinkKind::Device(device));
+    }
+
+    // Moves playback to another sink, picking up where it left off
+    pub fn set_sink(&mut self, sink: SinkKind) {
+        self.sink = sink;
+
         if !self.is_playing() {
             return;
         }
 
         self.update_position();
 
-        let current_position;
-        let current_path;
-        let was_paused;
+        let position = *self.current_position.lock().unwrap();
+        let path = self.current_path();
+        let paused = self.is_paused();
+        self.play_song_with_position(path, position, paused);
+    }
+
+    pub fn crossfade_settings(&self) -> CrossfadeSettings {
+        *self.crossfade.lock().unwrap()
+    }
 
-        {
-            current_position = *self.current_position.lock().unwrap();
-            current_path = self.current_path.lock().unwrap().clone();
-            was_paused = self.is_paused();
+    pub fn set_crossfade(&mut self, crossfade: CrossfadeSettings) {
+        *self.crossfade.lock().unwrap() = crossfade;
+    }
+
+    pub fn toggle_crossfade(&mut self) {
+        let mut crossfade = self.crossfade.lock().unwrap();
+        crossfade.enabled = !crossfade.enabled;
+    }
+
+    pub fn adjust_crossfade(&mut self, seconds: f32) {
+        let mut crossfade = self.crossfade.lock().unwrap();
+        crossfade.seconds = (crossfade.seconds + seconds).clamp(1.0, 12.0);
+    }
+
+    pub fn cycle_crossfade_curve(&mut self) {
+        let mut crossfade = self.crossfade.lock().unwrap();
+        crossfade.curve = match crossfade.curve {
+            FadeCurve::Linear => FadeCurve::EqualPower,
+            FadeCurve::EqualPower => FadeCurve::Linear,
+        };
+    }
+
+    pub fn toggle_crossfade_same_album(&mut self) {
+        let mut crossfade = self.crossfade.lock().unwrap();
+        crossfade.skip_same_album = !crossfade.skip_same_album;
+    }
+
+    pub fn equalizer_settings(&self) -> EqSettings {
+        *self.equalizer.lock().unwrap()
+    }
+
+    pub fn set_equalizer(&mut self, equalizer: EqSettings) {
+        *self.equalizer.lock().unwrap() = equalizer;
+    }
+
+    pub fn toggle_equalizer(&mut self) {
+        let mut equalizer = self.equalizer.lock().unwrap();
+        equalizer.enabled = !equalizer.enabled;
+    }
+
+    pub fn cycle_eq_preset(&mut self) {
+        self.equalizer.lock().unwrap().cycle_preset();
+    }
+
+    pub fn adjust_eq_band(&mut self, band: usize, amount: f32) {
+        self.equalizer.lock().unwrap().adjust_band(band, amount);
+    }
+
+    pub fn toggle_eq_genre_presets(&mut self) {
+        let mut equalizer = self.equalizer.lock().unwrap();
+        equalizer.genre_presets = !equalizer.genre_presets;
+    }
+
+    pub fn replay_gain_mode(&self) -> ReplayGainMode {
+        *self.replay_gain.lock().unwrap()
+    }
+
+    pub fn set_replay_gain_mode(&mut self, mode: ReplayGainMode) {
+        *self.replay_gain.lock().unwrap() = mode;
+    }
+
+    pub fn cycle_replay_gain_mode(&mut self) {
+        let mut mode = self.replay_gain.lock().unwrap();
+        *mode = match *mode {
+            ReplayGainMode::Off => ReplayGainMode::Track,
+            ReplayGainMode::Track => ReplayGainMode::Album,
+            ReplayGainMode::Album => ReplayGainMode::Off,
+        };
+    }
+
+    pub fn tempo(&self) -> Tempo {
+        *self.tempo.lock().unwrap()
+    }
+
+    pub fn adjust_speed(&mut self, amount: f32) {
+        let mut tempo = self.tempo();
+        tempo.speed = ((tempo.speed + amount) * 100.0).round().clamp(50.0, 200.0) / 100.0;
+        self.set_tempo(tempo);
+    }
+
+    pub fn adjust_pitch(&mut self, semitones: f32) {
+        let mut tempo = self.tempo();
+        tempo.semitones = (tempo.semitones + semitones).clamp(-12.0, 12.0);
+        self.set_tempo(tempo);
+    }
+
+    pub fn reset_tempo(&mut self) {
+        self.set_tempo(Tempo::default());
+    }
+
+    // Restarts decoding from what's audible now, so the buffered audio at
+    // the old speed isn't played out first
+    fn set_tempo(&mut self, tempo: Tempo) {
+        *self.tempo.lock().unwrap() = tempo;
+
+        if self.is_playing() {
+            self.update_position();
+            let position = *self.current_position.lock().unwrap();
+            self.seek_to(position);
         }
+    }
 
-        if current_path.is_none() {
+    pub fn ab_loop(&self) -> AbLoop {
+        self.ab_loop.lock().unwrap().clone()
+    }
+
+    // Marks A, then B, then clears the loop
+    pub fn cycle_ab_loop(&mut self) {
+        self.update_position();
+
+        let Some(path) = self.current_path() else {
             return;
+        };
+        let position = *self.current_position.lock().unwrap();
+
+        let mut ab_loop = self.ab_loop.lock().unwrap();
+        *ab_loop = match &*ab_loop {
+            AbLoop::Start(start_path, a) if *start_path == path && position > *a => {
+                AbLoop::Looping(path, *a, position)
+            }
+            AbLoop::Looping(..) => AbLoop::Off,
+            _ => AbLoop::Start(path, position),
+        };
+
+        // The decoder is already past B, so the first pass round starts here
+        if let AbLoop::Looping(_, a, _) = *ab_loop {
+            drop(ab_loop);
+            self.seek_to(a);
+        }
+    }
+
+    /*
+    pub fn restart(&mut self) {
+        let current_path = self.current_path.lock().unwrap().clone();
+        self.play_song(current_path);
+    }
+
+    pub fn jump_to_percent(&mut self, percent: f32) {
+        let total = *self.total_duration.lock().unwrap();
+        let target_duration = Duration::from_secs_f32(total.as_secs_f32() * percent.clamp(0.0, 1.0));
+
+        self.seek_to(target_duration);
+    }
+    */
+}
+
+// What the output callback shares with the player and the decode thread
+struct RenderShared {
+    // Frames queued before the last seek, which the callback skips rather than plays
+    discard_until: Arc<AtomicU64>,
+    is_paused: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+    fade_ms: Arc<AtomicU32>,
+    volume: Arc<AtomicU32>,
+    underruns: Arc<AtomicU32>,
+    frames_played: Arc<AtomicU64>,
+}
+
+// The output side of the ring: runs on the audio callback, so it only ever
+// touches the ring and atomics
+fn render_callback(
+    mut consumer: Consumer<f32>,
+    output: OutputFormat,
+    shared: RenderShared,
+    fade_in: bool,
+) -> Render {
+    let channel_count = output.channels;
+    let mut frames_read: u64 = 0;
+
+    // Volume changes glide over about 10 ms instead of stepping, which
+    // would be heard as zipper noise
+    let mut gain = f32::from_bits(shared.volume.load(Ordering::Relaxed));
+    let smoothing = 1.0 - (-1.0 / (output.rate as f32 * 0.01)).exp();
+
+    // Pausing, stopping and seeking fade out before the audio cuts, and
+    // resuming fades back in, so none of them click
+    let mut fade: f32 = if fade_in { 0.0 } else { 1.0 };
+
+    Box::new(move |data: &mut [f32]| {
+        let discard = shared.discard_until.load(Ordering::Acquire);
+        let silenced =
+            shared.is_paused.load(Ordering::Relaxed) || shared.stopping.load(Ordering::Relaxed);
+
+        let fade_frames =
+            shared.fade_ms.load(Ordering::Relaxed) as f32 * output.rate as f32 / 1000.0;
+        let step = 1.0 / fade_frames.max(1.0);
+
+        // Skipped frames count as played, so the stream frame count still
+        // lines up with the segments queued after the seek
+        if frames_read < discard && fade == 0.0 {
+            let skip = ((discard - frames_read) as usize * channel_count).min(consumer.slots());
+            if let Ok(chunk) = consumer.read_chunk(skip) {
+                chunk.commit_all();
+            }
+            frames_read += (skip / channel_count) as u64;
         }
 
-        let target_position = if current_position > Duration::from_secs_f32(seconds) {
-            current_position - Duration::from_secs_f32(seconds)
+        let seeking = frames_read < discard;
+        let fading_out = seeking || silenced;
+
+        let available = if silenced && fade == 0.0 {
+            data.fill(0.0);
+            0
         } else {
-            Duration::from_secs(0)
+            let target = f32::from_bits(shared.volume.load(Ordering::Relaxed));
+
+            let mut frames = consumer.slots().min(data.len()) / channel_count;
+            if seeking {
+                frames = frames.min((discard - frames_read) as usize);
+            }
+            if fading_out {
+                frames = frames.min((fade / step).ceil() as usize);
+            }
+            let available = frames * channel_count;
+
+            if available < data.len() && !fading_out {
+                shared.underruns.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // Whatever is left of a short buffer is still played, so the
+            // tail of a track drains instead of waiting for a full period
+            if let Ok(chunk) = consumer.read_chunk(available) {
+                let (first, second) = chunk.as_slices();
+                let samples = first.iter().chain(second);
+                for (index, (out, &sample)) in data.iter_mut().zip(samples).enumerate() {
+                    if index % channel_count == 0 {
+                        gain += (target - gain) * smoothing;
+                        fade = if fading_out {
+                            (fade - step).max(0.0)
+                        } else {
+                            (fade + step).min(1.0)
+                        };
+                    }
+
+                    let value = sample * gain * fade;
+                    *out = if gain > 1.0 { soft_limit(value) } else { value };
+                }
+                chunk.commit_all();
+            }
+            data[available..].fill(0.0);
+
+            frames_read += frames as u64;
+            available
         };
 
-        println!(
-            "Seeking backward from {:?} to {:?}",
-            current_position, target_position
-        );
+        shared.frames_played.store(frames_read, Ordering::Relaxed);
+        available
+    })
+}
 
-        {
-            let mut should_stop = self.should_stop.lock().unwrap();
-            *should_stop = true;
+// Leaves the signal alone up to the knee and bends anything louder smoothly
+// towards full scale, so boosted peaks don't hard clip
+fn soft_limit(sample: f32) -> f32 {
+    const KNEE: f32 = 0.8;
+
+    let level = sample.abs();
+    if level <= KNEE {
+        return sample;
+    }
+
+    let over = (level - KNEE) / (1.0 - KNEE);
+    sample.signum() * (KNEE + (1.0 - KNEE) * over.tanh())
+}
+
+// Moves as much of `samples` into the ring as there's room for
+fn push_samples(producer: &mut Producer<f32>, samples: &mut Vec<f32>) {
+    let count = producer.slots().min(samples.len());
+
+    if let Ok(chunk) = producer.write_chunk_uninit(count) {
+        chunk.fill_from_iter(samples.drain(..count));
+    }
+}
+
+// A stretch of the output stream that belongs to one track, starting at
+// `start_frame` frames into the stream and `start_offset` frames into the track.
+// Each frame of the stream covers `speed` frames of the track
+struct Segment {
+    start_frame: u64,
+    start_offset: u64,
+    speed: f64,
+    // Set for the jump back to A of an A–B loop, which isn't a track change
+    looped: bool,
+    path: PathBuf,
+    duration: Arc<Mutex<Option<Duration>>>,
+}
+
+struct Source {
+    path: PathBuf,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    spec: SignalSpec,
+    duration: Arc<Mutex<Option<Duration>>>,
+    sample_buf: Option<SampleBuffer<f32>>,
+    pending: Vec<f32>,
+    trim_until: Option<u64>,
+    position: u64,
+    output: OutputFormat,
+    converter: Option<Converter>,
+    raw: Vec<f32>,
+    flushed: bool,
+    replay_gain: ReplayGain,
+    gain: f32,
+    album: Option<String>,
+    genre: Option<String>,
+}
+
+impl Source {
+    fn open(path: &Path, events: &Sender<PlayerEvent>) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Error opening audio file: {}", e))?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        // Gapless mode trims encoder delay and padding (LAME/iTunSMPB) so
+        // consecutive tracks join without silence
+        let format_opts = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let metadata_opts: MetadataOptions = Default::default();
+        let decoder_opts: DecoderOptions = Default::default();
+
+        let probed = symphonia::default::get_probe()
+            .format(&Hint::new(), mss, &format_opts, &metadata_opts)
+            .map_err(|e| format!("Error probing audio format: {}", e))?;
+
+        let format = probed.format;
+
+        let track = format
+            .default_track()
+            .ok_or_else(|| "No default track found in audio file".to_string())?;
+
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &decoder_opts)
+            .map_err(|e| format!("Error creating decoder: {}", e))?;
+
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+
+        let container_duration = track
+            .codec_params
+            .n_frames
+            .zip(track.codec_params.sample_rate)
+            .map(|(n_frames, rate)| Duration::from_secs_f64(n_frames as f64 / rate as f64));
+        let duration = Arc::new(Mutex::new(container_duration));
+
+        if let Some(total) = container_duration {
+            let _ = events.send(PlayerEvent::DurationKnown(path.to_path_buf(), total));
+        } else {
+            // The header has no frame count, so count packets on the side
+            // rather than holding up playback
+            let scan_path = path.to_path_buf();
+            let scanned = Arc::clone(&duration);
+            let events = events.clone();
+
+            thread::spawn(move || {
+                if let Some(total) = scan_duration(&scan_path) {
+                    *scanned.lock().unwrap() = Some(total);
+                    let _ = events.send(PlayerEvent::DurationKnown(scan_path, total));
+                }
+            });
         }
 
-        thread::sleep(Duration::from_millis(50));
+        let tags = get_playback_tags(path.to_str().unwrap_or(""));
+
+        let mut source = Source {
+            path: path.to_path_buf(),
+            format,
+            decoder,
+            track_id,
+            time_base,
+            spec: SignalSpec::new(0, Channels::empty()),
+            duration,
+            sample_buf: None,
+            pending: Vec::new(),
+            trim_until: None,
+            position: 0,
+            output: OutputFormat {
+                rate: 0,
+                channels: 0,
+                sample_format: SampleFormat::F32,
+            },
+            converter: None,
+            raw: Vec::new(),
+            flushed: false,
+            replay_gain: loudness::fill_in(path, tags.replay_gain),
+            gain: 1.0,
+            album: tags.album,
+            genre: tags.genre,
+        };
 
-        {
-            let mut buffer = self.audio_buffer.lock().unwrap();
-            buffer.clear();
+        // The output spec is only reliable once a packet has been decoded
+        let mut first = Vec::new();
+        if !source.decode_packet(&mut first) || source.sample_buf.is_none() {
+            return Err("Error decoding first audio packet".to_string());
         }
+        source.pending = first;
+        source.set_output(OutputFormat {
+            rate: source.spec.rate,
+            channels: source.spec.channels.count(),
+            sample_format: SampleFormat::F32,
+        });
+
+        Ok(source)
+    }
 
-        let thread_handle = self.thread_handle.take();
-        if let Some(handle) = thread_handle {
-            std::mem::forget(handle);
+    fn segment(&self, start_frame: u64, start_offset: u64, tempo: Tempo) -> Segment {
+        Segment {
+            start_frame,
+            start_offset,
+            speed: tempo.speed as f64,
+            looped: false,
+            path: self.path.clone(),
+            duration: Arc::clone(&self.duration),
         }
+    }
 
-        {
-            let mut is_playing = self.is_playing.lock().unwrap();
-            *is_playing = false;
+    fn set_output(&mut self, output: OutputFormat) {
+        let rate = self.spec.rate;
+        let channels = self.spec.channels.count();
+
+        self.output = output;
+        self.converter = (output.rate != rate || output.channels != channels)
+            .then(|| Converter::new(rate, channels, output.rate, output.channels));
+    }
+
+    fn set_gain(&mut self, mode: ReplayGainMode) {
+        self.gain = mode.gain(&self.replay_gain);
+    }
+
+    // Converts a frame count at the file's rate to one at the output rate
+    fn output_frames(&self, frames: u64) -> u64 {
+        frames * self.output.rate as u64 / self.spec.rate.max(1) as u64
+    }
+
+    // Returns the frame position in the track that playback resumes from
+    fn seek(&mut self, position: Duration) -> Result<u64, symphonia::core::errors::Error> {
+        let seeked_to = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(position),
+                track_id: Some(self.track_id),
+            },
+        )?;
+        self.decoder.reset();
+        self.pending.clear();
+        self.trim_until = Some(seeked_to.required_ts);
+        self.position = ts_to_frames(seeked_to.required_ts, self.time_base, self.spec.rate);
+        self.flushed = false;
+
+        if let Some(converter) = self.converter.as_mut() {
+            converter.reset();
+        }
+
+        Ok(self.output_frames(self.position))
+    }
+
+    // Output frames of the last packet that lie past `time`, once it's been reached
+    fn frames_past(&self, time: Duration) -> Option<usize> {
+        let frames = (time.as_secs_f64() * self.spec.rate as f64) as u64;
+        let past = self.position.checked_sub(frames)?;
+        Some(self.output_frames(past) as usize)
+    }
+
+    // Frames left to play, counted at the output rate
+    fn remaining_frames(&self) -> Option<u64> {
+        let duration = (*self.duration.lock().unwrap())?;
+        let total = (duration.as_secs_f64() * self.spec.rate as f64) as u64;
+        Some(self.output_frames(total.saturating_sub(self.position)))
+    }
+
+    // Appends the next packet's interleaved samples, returning false at the end of the track
+    fn read_packet(&mut self, out: &mut Vec<f32>) -> bool {
+        let before = out.len();
+        let mut raw = std::mem::take(&mut self.raw);
+        raw.clear();
+
+        let more = if !self.pending.is_empty() {
+            raw.append(&mut self.pending);
+            true
+        } else {
+            self.decode_packet(&mut raw)
+        };
+
+        if more {
+            self.position += (raw.len() / self.spec.channels.count().max(1)) as u64;
+
+            match self.converter.as_mut() {
+                Some(converter) => converter.process(&raw, out),
+                None => out.extend_from_slice(&raw),
+            }
+        } else if !self.flushed {
+            self.flushed = true;
+
+            if let Some(converter) = self.converter.as_mut() {
+                converter.flush(out);
+            }
         }
 
-        thread::sleep(Duration::from_millis(100));
+        if self.gain != 1.0 {
+            for sample in &mut out[before..] {
+                *sample *= self.gain;
+            }
+        }
 
-        let path = current_path.unwrap();
-        self.play_song_with_position(Some(path), target_position, was_paused);
+        self.raw = raw;
+        more || out.len() > before
     }
 
-    pub fn increase_volume(&mut self, amount: f32) {
-        let mut volume = self.current_volume.lock().unwrap();
-        *volume = (*volume + amount).min(1.0);
+    fn decode_packet(&mut self, out: &mut Vec<f32>) -> bool {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => return false,
+            };
+
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let audio_buf = match self.decoder.decode(&packet) {
+                Ok(audio_buf) => audio_buf,
+                Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
+                Err(_) => return false,
+            };
+
+            let spec = *audio_buf.spec();
+            let channel_count = spec.channels.count();
+            let needed = audio_buf.capacity() * channel_count;
+
+            let sample_buf = match &mut self.sample_buf {
+                Some(sample_buf) if sample_buf.capacity() >= needed => sample_buf,
+                sample_buf => {
+                    self.spec = spec;
+                    sample_buf.insert(SampleBuffer::<f32>::new(audio_buf.capacity() as u64, spec))
+                }
+            };
+            sample_buf.copy_interleaved_ref(audio_buf);
+
+            let skip = self.trim_until.map_or(0, |target| {
+                let frames = ts_to_frames(
+                    target.saturating_sub(packet.ts()),
+                    self.time_base,
+                    self.spec.rate,
+                );
+                frames as usize * channel_count
+            });
+            self.trim_until = self
+                .trim_until
+                .filter(|&target| packet.ts() + packet.dur() < target);
+
+            let samples = sample_buf.samples();
+            out.extend_from_slice(&samples[skip.min(samples.len())..]);
+            return true;
+        }
     }
+}
+
+struct Crossfade {
+    next: Source,
+    incoming: VecDeque<f32>,
+    frame: u64,
+    length: u64,
+    curve: FadeCurve,
+}
 
-    pub fn decrease_volume(&mut self, amount: f32) {
-        let mut volume = self.current_volume.lock().unwrap();
-        *volume = (*volume - amount).max(0.0);
+impl Crossfade {
+    fn new(next: Source, length: u64, curve: FadeCurve) -> Self {
+        Self {
+            next,
+            incoming: VecDeque::new(),
+            frame: 0,
+            length,
+            curve,
+        }
     }
 
-    pub fn get_volume(&self) -> f32 {
-        *self.current_volume.lock().unwrap()
+    // Mixes the incoming track into a packet of the outgoing one in place
+    fn mix(&mut self, samples: &mut [f32], channels: usize) {
+        let mut more = Vec::new();
+        while self.incoming.len() < samples.len() && self.next.read_packet(&mut more) {
+            self.incoming.extend(more.drain(..));
+        }
+
+        for frame in samples.chunks_mut(channels) {
+            let t = (self.frame as f32 / self.length as f32).min(1.0);
+            let (fade_out, fade_in) = self.curve.gains(t);
+
+            for sample in frame {
+                let incoming = self.incoming.pop_front().unwrap_or(0.0);
+                *sample = *sample * fade_out + incoming * fade_in;
+            }
+
+            self.frame += 1;
+        }
     }
 
-    /*
-    pub fn restart(&mut self) {
-        let current_path = self.current_path.lock().unwrap().clone();
-        self.play_song(current_path);
+    fn finish(self) -> (Source, Vec<f32>) {
+        (self.next, self.incoming.into())
     }
+}
 
-    pub fn jump_to_percent(&mut self, percent: f32) {
-        let total = *self.total_duration.lock().unwrap();
-        let target_duration = Duration::from_secs_f32(total.as_secs_f32() * percent.clamp(0.0, 1.0));
+// The next track, opened on a thread of its own ahead of time so a slow disk
+// or network share doesn't hold up the decoder when the current one ends
+struct Preopen {
+    path: PathBuf,
+    opened: Receiver<Result<Source, String>>,
+}
+
+impl Preopen {
+    fn start(path: PathBuf, events: &Sender<PlayerEvent>) -> Self {
+        let (sender, opened) = mpsc::channel();
+        let thread_path = path.clone();
+        let events = events.clone();
 
-        let mut position = self.current_position.lock().unwrap();
-        *position = target_duration;
+        thread::spawn(move || {
+            let _ = sender.send(Source::open(&thread_path, &events));
+        });
 
-        let mut playback_started = self.playback_started.lock().unwrap();
-        *playback_started = Some(Instant::now() - target_duration);
+        Self { path, opened }
+    }
+
+    // Keeps a track that was opened but not taken yet
+    fn ready(source: Source) -> Self {
+        let (sender, opened) = mpsc::channel();
+        let path = source.path.clone();
+        let _ = sender.send(Ok(source));
+
+        Self { path, opened }
+    }
+
+    // Waits for the open to finish if it's still going
+    fn wait(self) -> Result<Source, String> {
+        self.opened
+            .recv()
+            .unwrap_or_else(|_| Err("Error opening audio file".to_string()))
+    }
+}
+
+// Opens the track to play: `first` if given, otherwise the next queued one,
+// advancing the queue to it if `accept` agrees. Tracks that can't be opened
+// are reported and passed over for the ones queued after them, at most once
+// round the queue
+fn open_queued_source(
+    queue: &Mutex<PlayQueue>,
+    mut first: Option<PathBuf>,
+    preopened: &mut Option<Preopen>,
+    events: &Sender<PlayerEvent>,
+    accept: impl Fn(&Source) -> bool,
+) -> Option<Source> {
+    let attempts = queue.lock().unwrap().tracks().len().max(1);
+
+    for _ in 0..attempts {
+        let queued = first.is_none();
+        let path = match first.take() {
+            Some(path) => path,
+            None => queue.lock().unwrap().peek_next().cloned()?,
+        };
+
+        let opened = match preopened.take() {
+            Some(preopen) if preopen.path == path => preopen.wait(),
+            _ => Source::open(&path, events),
+        };
+
+        let opened = match opened {
+            Ok(source) if !accept(&source) => {
+                *preopened = Some(Preopen::ready(source));
+                return None;
+            }
+            Ok(source) => Some(source),
+            Err(e) => {
+                let _ = events.send(PlayerEvent::Error(format!(
+                    "Skipping {}: {}",
+                    path.display(),
+                    e
+                )));
+                None
+            }
+        };
+
+        // The queue may have changed while the file was being opened
+        if queued {
+            let mut queue = queue.lock().unwrap();
+            if queue.peek_next() != Some(&path) {
+                return None;
+            }
+            queue.next();
+        }
+
+        if opened.is_some() {
+            return opened;
+        }
+    }
+
+    None
+}
+
+fn same_album(a: &Source, b: &Source) -> bool {
+    a.album.is_some() && a.album == b.album
+}
+
+// Drops segments the output has already played past, leaving the audible one
+// in front, and reports each of those track changes
+fn drop_played_segments(
+    segments: &mut VecDeque<Segment>,
+    frames_played: u64,
+    events: &Sender<PlayerEvent>,
+) {
+    while segments
+        .get(1)
+        .is_some_and(|next| next.start_frame <= frames_played)
+    {
+        let ended = segments.pop_front();
+
+        if let Some(ended) = ended
+            && let Some(started) = segments.front()
+            && !started.looped
+        {
+            let _ = events.send(PlayerEvent::TrackEnded(ended.path));
+            let _ = events.send(PlayerEvent::TrackStarted(started.path.clone()));
+        }
     }
-    */
+}
+
+fn ts_to_frames(ts: u64, time_base: Option<TimeBase>, rate: u32) -> u64 {
+    match time_base {
+        Some(time_base) => {
+            let time = time_base.calc_time(ts);
+            ((time.seconds as f64 + time.frac) * rate as f64).round() as u64
+        }
+        None => ts,
+    }
+}
+
+// Sums packet durations for files whose header doesn't carry a frame count
+fn scan_duration(path: &Path) -> Option<Duration> {
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let probed = symphonia::default::get_probe()
+        .format(
+            &Hint::new(),
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+    let mut format = probed.format;
+
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let time_base = track.codec_params.time_base;
+    let sample_rate = track.codec_params.sample_rate?;
+
+    let mut total_ts = 0;
+    while let Ok(packet) = format.next_packet() {
+        if packet.track_id() == track_id {
+            total_ts += packet.dur();
+        }
+    }
+
+    let frames = ts_to_frames(total_ts, time_base, sample_rate);
+    Some(Duration::from_secs_f64(frames as f64 / sample_rate as f64))
 }
 
 impl Drop for AudioPlayer {
@@ -614,3 +1855,288 @@ impl Drop for AudioPlayer {
         self.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    // A short stereo sine in a WAV file of its own under the temp directory
+    fn sine_wav(name: &str, frames: u32) -> PathBuf {
+        let path = temp_path(&format!("{}.wav", name));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for frame in 0..frames {
+            let value = (frame as f32 * 440.0 * std::f32::consts::TAU / RATE as f32).sin();
+            let sample = (value * i16::MAX as f32 * 0.5) as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        path
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tui_player_test_{}_{}", std::process::id(), name))
+    }
+
+    // Drives the player like the interface does until it stops, collecting its events
+    fn play_out(player: &mut AudioPlayer) -> Vec<PlayerEvent> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut events = Vec::new();
+
+        while player.is_playing() {
+            assert!(Instant::now() < deadline, "playback never finished");
+            player.advance_if_finished();
+            player.update_position();
+            events.extend(player.poll_events());
+            thread::sleep(Duration::from_millis(5));
+        }
+        player.advance_if_finished();
+        events.extend(player.poll_events());
+
+        events
+    }
+
+    fn started(events: &[PlayerEvent]) -> Vec<PathBuf> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                PlayerEvent::TrackStarted(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn wav_frames(path: &Path) -> u32 {
+        hound::WavReader::open(path).unwrap().duration()
+    }
+
+    #[test]
+    fn queued_tracks_play_one_after_another() {
+        let a = sine_wav("advance_a", RATE / 2);
+        let b = sine_wav("advance_b", RATE / 2);
+
+        let mut player = AudioPlayer::new(SinkKind::Null { realtime: false });
+        player.enqueue(a.clone());
+        player.enqueue(b.clone());
+        player.play_next();
+
+        let events = play_out(&mut player);
+
+        assert_eq!(started(&events), [a.clone(), b.clone()]);
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, PlayerEvent::TrackEnded(path) if *path == b))
+        );
+        assert_eq!(player.queue().current_index(), Some(1));
+    }
+
+    #[test]
+    fn unreadable_tracks_are_skipped() {
+        let a = sine_wav("skip_a", RATE / 4);
+        let missing = temp_path("skip_missing.wav");
+        let b = sine_wav("skip_b", RATE / 4);
+
+        let mut player = AudioPlayer::new(SinkKind::Null { realtime: false });
+        player.play_tracks(vec![a.clone(), missing, b.clone()], false);
+
+        let events = play_out(&mut player);
+
+        assert_eq!(started(&events), [a, b]);
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, PlayerEvent::Error(_)))
+        );
+    }
+
+    #[test]
+    fn an_unreadable_first_track_is_skipped() {
+        let missing = temp_path("first_missing.wav");
+        let a = sine_wav("first_a", RATE / 4);
+        let b = sine_wav("first_b", RATE / 4);
+
+        let mut player = AudioPlayer::new(SinkKind::Null { realtime: false });
+        player.play_tracks(vec![missing, a.clone(), b.clone()], false);
+
+        let events = play_out(&mut player);
+
+        assert_eq!(started(&events), [a, b]);
+        assert_eq!(player.queue().current_index(), Some(2));
+    }
+
+    #[test]
+    fn the_audible_track_lags_the_decoder_in_the_queue() {
+        let a = sine_wav("audible_a", RATE * 2);
+        let b = sine_wav("audible_b", RATE);
+
+        let mut player = AudioPlayer::new(SinkKind::Null { realtime: true });
+        player.play_tracks(vec![a, b], false);
+
+        // The decoder fills the ring well ahead of real time, into the second track
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while player.queue().current_index() != Some(1) {
+            assert!(Instant::now() < deadline, "decoder never moved on");
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(player.audible_queue_index(), Some(0));
+        player.stop();
+        assert_eq!(player.queue().current_index(), Some(0));
+    }
+
+    #[test]
+    fn wav_output_is_as_long_as_the_source() {
+        let source = sine_wav("length_source", RATE * 3 / 2);
+        let output = temp_path("length_output.wav");
+
+        let mut player = AudioPlayer::new(SinkKind::Wav(output.clone()));
+        player.play_song(Some(source));
+        play_out(&mut player);
+
+        assert_eq!(wav_frames(&output), RATE * 3 / 2);
+    }
+
+    #[test]
+    fn gapless_wav_output_joins_both_tracks() {
+        let a = sine_wav("gapless_a", RATE);
+        let b = sine_wav("gapless_b", RATE / 2);
+        let output = temp_path("gapless_output.wav");
+
+        let mut player = AudioPlayer::new(SinkKind::Wav(output.clone()));
+        player.play_tracks(vec![a, b], false);
+        play_out(&mut player);
+
+        assert_eq!(wav_frames(&output), RATE * 3 / 2);
+    }
+
+    #[test]
+    fn seeking_moves_the_position_and_skips_the_audio_before_it() {
+        let source = sine_wav("seek_source", RATE * 2);
+        let output = temp_path("seek_output.wav");
+
+        // Started paused, so nothing plays before the seek lands
+        let mut player = AudioPlayer::new(SinkKind::Wav(output.clone()));
+        player.play_song_with_position(Some(source), Duration::ZERO, true);
+        player.seek_to(Duration::from_millis(500));
+
+        // The position reads the target straight away, but the seek has only landed
+        // once the length is known from the opened file
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            player.update_position();
+            if !player.duration().is_zero() && player.position() == Duration::from_millis(500) {
+                break;
+            }
+            assert!(Instant::now() < deadline, "seek never landed");
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        player.toggle_pause();
+        play_out(&mut player);
+
+        assert_eq!(wav_frames(&output), RATE * 3 / 2);
+    }
+
+    #[test]
+    fn seeks_in_quick_succession_add_up() {
+        let source = sine_wav("seek_repeat_source", RATE * 4);
+
+        let mut player = AudioPlayer::new(SinkKind::Null { realtime: false });
+        player.play_song_with_position(Some(source), Duration::ZERO, true);
+
+        // Seeks only go by the stream's position once the track is open
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while player.duration().is_zero() {
+            assert!(Instant::now() < deadline, "track never opened");
+            player.update_position();
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        for _ in 0..3 {
+            player.seek_forward(1.0);
+        }
+        assert_eq!(player.position(), Duration::from_secs(3));
+
+        // Once the decode thread has taken the last seek, the stream agrees
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while player.pending_seek.lock().unwrap().is_some() {
+            assert!(Instant::now() < deadline, "seek never landed");
+            thread::sleep(Duration::from_millis(5));
+        }
+        player.update_position();
+        assert_eq!(player.position(), Duration::from_secs(3));
+
+        player.stop();
+    }
+
+    // Every core is kept busy while the interface polls the player as fast as
+    // it can, and the output has to keep to real time throughout. The output
+    // here is the null sink's render thread taking 10 ms blocks, so this covers
+    // the decoder keeping the ring full, not a cpal callback; the device side
+    // is covered by the chunked conversion tests in the sink module
+    #[test]
+    fn playback_keeps_up_under_load() {
+        let source = sine_wav("load_source", RATE * 3);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let cores = thread::available_parallelism().map_or(4, |count| count.get());
+        let burners: Vec<_> = (0..cores * 2)
+            .map(|_| {
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let mut value = 0u64;
+                    while !stop.load(Ordering::Relaxed) {
+                        value = std::hint::black_box(value.wrapping_mul(31).wrapping_add(1));
+                    }
+                })
+            })
+            .collect();
+
+        let mut player = AudioPlayer::new(SinkKind::Null { realtime: true });
+        player.play_song(Some(source));
+
+        // The first period can beat the decoder to the ring, so counting
+        // starts once it's had a moment to fill
+        thread::sleep(Duration::from_millis(200));
+        let before = player.underruns();
+
+        let until = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < until {
+            player.update_position();
+            player.poll_events();
+            let _ = player.queue().tracks().len();
+        }
+        let underruns = player.underruns() - before;
+
+        stop.store(true, Ordering::Relaxed);
+        for burner in burners {
+            burner.join().unwrap();
+        }
+        player.stop();
+
+        assert_eq!(underruns, 0);
+    }
+
+    #[test]
+    fn position_stops_at_the_end_of_the_track() {
+        let source = sine_wav("position_source", RATE);
+
+        let mut player = AudioPlayer::new(SinkKind::Null { realtime: false });
+        player.play_song(Some(source));
+        play_out(&mut player);
+        player.update_position();
+
+        assert_eq!(player.position(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,528 @@
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Repeat {
+    #[default]
+    Off,
+    All,
+    One,
+}
+
+impl Repeat {
+    pub fn name(self) -> &'static str {
+        match self {
+            Repeat::Off => "off",
+            Repeat::All => "all",
+            Repeat::One => "one",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Shuffle {
+    #[default]
+    Off,
+    // Every pick is independent, so tracks can come round again at any time
+    Random,
+    // Every track plays once before any plays again
+    Bag,
+}
+
+impl Shuffle {
+    pub fn name(self) -> &'static str {
+        match self {
+            Shuffle::Off => "off",
+            Shuffle::Random => "random",
+            Shuffle::Bag => "no repeats",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PlayQueue {
+    tracks: Vec<PathBuf>,
+    current: Option<usize>,
+    repeat: Repeat,
+    shuffle: Shuffle,
+    // Tracks left behind by `next` and `skip`, so `previous` can retrace them
+    history: Vec<usize>,
+    // Shuffled picks still to come, the next one last
+    upcoming: Vec<usize>,
+    // Goes up whenever the tracks change, so a saved copy can tell it's stale
+    revision: u64,
+}
+
+impl PlayQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tracks(&self) -> &[PathBuf] {
+        &self.tracks
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.repeat
+    }
+
+    pub fn shuffle(&self) -> Shuffle {
+        self.shuffle
+    }
+
+    pub fn cycle_repeat(&mut self) {
+        self.repeat = match self.repeat {
+            Repeat::Off => Repeat::All,
+            Repeat::All => Repeat::One,
+            Repeat::One => Repeat::Off,
+        };
+        self.refill();
+    }
+
+    pub fn cycle_shuffle(&mut self) {
+        self.set_shuffle(match self.shuffle {
+            Shuffle::Off => Shuffle::Random,
+            Shuffle::Random => Shuffle::Bag,
+            Shuffle::Bag => Shuffle::Off,
+        });
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: Shuffle) {
+        self.shuffle = shuffle;
+
+        self.upcoming.clear();
+        if self.shuffle == Shuffle::Bag {
+            self.fill_bag();
+        }
+        self.refill();
+    }
+
+    // The track that follows when the current one finishes
+    pub fn peek_next(&self) -> Option<&PathBuf> {
+        self.tracks.get(self.next_index()?)
+    }
+
+    pub fn enqueue(&mut self, path: PathBuf) {
+        self.tracks.push(path);
+        self.revision += 1;
+        self.bag_insert(self.tracks.len() - 1);
+        self.refill();
+    }
+
+    pub fn insert_next(&mut self, path: PathBuf) {
+        let index = self.after_current().min(self.tracks.len());
+        self.tracks.insert(index, path);
+        self.revision += 1;
+        self.shift_from(index);
+
+        // While shuffling, "next" means the next pick
+        if self.shuffle != Shuffle::Off {
+            self.upcoming.push(index);
+        }
+    }
+
+    // Puts the track right after the current one and makes it current
+    pub fn play_now(&mut self, path: PathBuf) -> PathBuf {
+        let index = self.after_current().min(self.tracks.len());
+        self.tracks.insert(index, path.clone());
+        self.revision += 1;
+        self.shift_from(index);
+        self.history.clear();
+        self.current = Some(index);
+        self.refill();
+        path
+    }
+
+    pub fn jump_to(&mut self, index: usize) -> Option<PathBuf> {
+        let path = self.tracks.get(index)?.clone();
+        self.history.clear();
+        self.current = Some(index);
+        self.upcoming.retain(|&upcoming| upcoming != index);
+        self.refill();
+        Some(path)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<PathBuf> {
+        if index >= self.tracks.len() {
+            return None;
+        }
+
+        let removed = self.tracks.remove(index);
+        self.revision += 1;
+
+        // Removing the current track keeps the cursor just before it, so
+        // `next` picks up whatever followed the removed track.
+        self.current = match self.current {
+            Some(current) if index < current => Some(current - 1),
+            Some(current) if index == current => current.checked_sub(1),
+            other => other,
+        };
+
+        self.remap(|track| match track {
+            track if track == index => None,
+            track if track > index => Some(track - 1),
+            track => Some(track),
+        });
+        self.refill();
+
+        Some(removed)
+    }
+
+    pub fn move_track(&mut self, from: usize, to: usize) {
+        if from >= self.tracks.len() || to >= self.tracks.len() || from == to {
+            return;
+        }
+
+        let track = self.tracks.remove(from);
+        self.tracks.insert(to, track);
+        self.revision += 1;
+
+        let moved = |index: usize| {
+            if index == from {
+                to
+            } else if from < index && to >= index {
+                index - 1
+            } else if from > index && to <= index {
+                index + 1
+            } else {
+                index
+            }
+        };
+
+        self.current = self.current.map(moved);
+        self.remap(|index| Some(moved(index)));
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.revision += 1;
+        self.current = None;
+        self.history.clear();
+        self.upcoming.clear();
+    }
+
+    // Moves on when a track finishes, which repeat-one keeps on the same track
+    pub fn next(&mut self) -> Option<PathBuf> {
+        let index = self.next_index()?;
+        self.advance_to(index)
+    }
+
+    // Moves on at the listener's request, past a repeated track
+    pub fn skip(&mut self) -> Option<PathBuf> {
+        let index = self.skip_index()?;
+        self.advance_to(index)
+    }
+
+    pub fn previous(&mut self) -> Option<PathBuf> {
+        let index = match self.history.pop() {
+            Some(index) => index,
+            None => self.current?.checked_sub(1)?,
+        };
+        let path = self.tracks.get(index)?.clone();
+
+        // Going back puts the track being left at the front of the shuffle again
+        if let Some(current) = self.current
+            && current != index
+            && self.shuffle != Shuffle::Off
+        {
+            self.upcoming.retain(|&upcoming| upcoming != current);
+            self.upcoming.push(current);
+        }
+
+        self.current = Some(index);
+        Some(path)
+    }
+
+    // Where `previous` would land after being called `steps` times, without going there
+    pub fn index_back(&self, steps: usize) -> Option<usize> {
+        let retraced = self.history.len().min(steps);
+        let index = match retraced {
+            0 => self.current?,
+            retraced => self.history[self.history.len() - retraced],
+        };
+
+        Some(index.saturating_sub(steps - retraced))
+    }
+
+    fn advance_to(&mut self, index: usize) -> Option<PathBuf> {
+        let path = self.tracks.get(index)?.clone();
+
+        if let Some(current) = self.current {
+            self.history.push(current);
+        }
+        self.current = Some(index);
+        self.upcoming.retain(|&upcoming| upcoming != index);
+        self.refill();
+
+        Some(path)
+    }
+
+    fn next_index(&self) -> Option<usize> {
+        match (self.repeat, self.current) {
+            (Repeat::One, Some(current)) => Some(current),
+            _ => self.skip_index(),
+        }
+    }
+
+    fn skip_index(&self) -> Option<usize> {
+        if self.shuffle != Shuffle::Off {
+            return self.upcoming.last().copied();
+        }
+
+        let index = self.after_current();
+        if index < self.tracks.len() {
+            Some(index)
+        } else if self.repeat != Repeat::Off && !self.tracks.is_empty() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    fn after_current(&self) -> usize {
+        self.current.map_or(0, |current| current + 1)
+    }
+
+    // Keeps a shuffled pick lined up, so the next track is known before it's taken
+    fn refill(&mut self) {
+        if !self.upcoming.is_empty() || self.tracks.is_empty() {
+            return;
+        }
+
+        match self.shuffle {
+            Shuffle::Off => {}
+            Shuffle::Random => {
+                let others: Vec<usize> = (0..self.tracks.len())
+                    .filter(|&index| Some(index) != self.current || self.tracks.len() == 1)
+                    .collect();
+                self.upcoming.push(others[fastrand::usize(..others.len())]);
+            }
+            // An exhausted bag only starts over when repeating the whole queue
+            Shuffle::Bag if self.repeat != Repeat::Off => self.fill_bag(),
+            Shuffle::Bag => {}
+        }
+    }
+
+    fn fill_bag(&mut self) {
+        self.upcoming = (0..self.tracks.len())
+            .filter(|&index| Some(index) != self.current)
+            .collect();
+        fastrand::shuffle(&mut self.upcoming);
+    }
+
+    // Newly added tracks join the bag somewhere random
+    fn bag_insert(&mut self, index: usize) {
+        if self.shuffle == Shuffle::Bag {
+            let position = fastrand::usize(..=self.upcoming.len());
+            self.upcoming.insert(position, index);
+        }
+    }
+
+    // Makes room in the stored indices for a track inserted at `index`
+    fn shift_from(&mut self, index: usize) {
+        self.remap(|track| Some(if track >= index { track + 1 } else { track }));
+    }
+
+    fn remap(&mut self, map: impl Fn(usize) -> Option<usize>) {
+        self.history = self
+            .history
+            .iter()
+            .filter_map(|&index| map(index))
+            .collect();
+        self.upcoming = self
+            .upcoming
+            .iter()
+            .filter_map(|&index| map(index))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(names: &[&str]) -> PlayQueue {
+        let mut queue = PlayQueue::new();
+        for name in names {
+            queue.enqueue(PathBuf::from(name));
+        }
+        queue
+    }
+
+    fn name(path: Option<PathBuf>) -> Option<String> {
+        path.map(|path| path.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn plays_through_in_order_and_stops() {
+        let mut queue = queue(&["a", "b", "c"]);
+
+        assert_eq!(name(queue.next()).as_deref(), Some("a"));
+        assert_eq!(name(queue.next()).as_deref(), Some("b"));
+        assert_eq!(name(queue.next()).as_deref(), Some("c"));
+        assert_eq!(queue.next(), None);
+        assert_eq!(queue.current_index(), Some(2));
+    }
+
+    #[test]
+    fn previous_retraces_history() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.next();
+        queue.next();
+        queue.next();
+
+        assert_eq!(name(queue.previous()).as_deref(), Some("b"));
+        assert_eq!(name(queue.previous()).as_deref(), Some("a"));
+        assert_eq!(queue.previous(), None);
+        assert_eq!(name(queue.next()).as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn index_back_matches_where_previous_goes() {
+        let played = || {
+            let mut queue = queue(&["a", "b", "c", "d"]);
+            queue.jump_to(1);
+            queue.next();
+            queue.next();
+            queue
+        };
+
+        for steps in 0..4 {
+            let mut retraced = played();
+            for _ in 0..steps {
+                retraced.previous();
+            }
+            assert_eq!(played().index_back(steps), retraced.current_index());
+        }
+    }
+
+    #[test]
+    fn repeat_one_holds_on_next_but_not_skip() {
+        let mut queue = queue(&["a", "b"]);
+        queue.next();
+        queue.cycle_repeat();
+        queue.cycle_repeat();
+        assert!(queue.repeat() == Repeat::One);
+
+        assert_eq!(name(queue.next()).as_deref(), Some("a"));
+        assert_eq!(name(queue.skip()).as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn repeat_all_wraps_around() {
+        let mut queue = queue(&["a", "b"]);
+        queue.cycle_repeat();
+        queue.next();
+        queue.next();
+
+        assert_eq!(name(queue.next()).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn insert_next_and_play_now_go_after_current() {
+        let mut queue = queue(&["a", "b"]);
+        queue.next();
+
+        queue.insert_next(PathBuf::from("x"));
+        assert_eq!(queue.peek_next(), Some(&PathBuf::from("x")));
+
+        queue.play_now(PathBuf::from("y"));
+        assert_eq!(queue.current_index(), Some(1));
+        let names: Vec<_> = queue.tracks().iter().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(names, ["a", "y", "x", "b"]);
+    }
+
+    #[test]
+    fn removing_the_current_track_continues_with_the_one_after() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.next();
+        queue.next();
+
+        queue.remove(1);
+        assert_eq!(queue.current_index(), Some(0));
+        assert_eq!(name(queue.next()).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn removing_before_the_current_track_keeps_it_current() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.next();
+        queue.next();
+
+        queue.remove(0);
+        assert_eq!(queue.current_index(), Some(0));
+        assert_eq!(name(queue.next()).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn moving_a_track_carries_the_current_index() {
+        let mut queue = queue(&["a", "b", "c"]);
+        queue.next();
+
+        queue.move_track(0, 2);
+        assert_eq!(queue.current_index(), Some(2));
+        assert_eq!(queue.tracks()[2], PathBuf::from("a"));
+
+        queue.move_track(1, 0);
+        assert_eq!(queue.current_index(), Some(2));
+        assert_eq!(queue.tracks()[0], PathBuf::from("c"));
+    }
+
+    #[test]
+    fn bag_shuffle_plays_every_track_once() {
+        let names: Vec<String> = (0..20).map(|index| index.to_string()).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let mut queue = queue(&names);
+        queue.set_shuffle(Shuffle::Bag);
+
+        let mut played = Vec::new();
+        while let Some(path) = queue.next() {
+            played.push(path);
+        }
+        played.sort();
+        played.dedup();
+
+        assert_eq!(played.len(), 20);
+    }
+
+    #[test]
+    fn previous_while_shuffling_comes_back_to_the_same_pick() {
+        let mut queue = queue(&["a", "b", "c", "d"]);
+        queue.set_shuffle(Shuffle::Bag);
+        let first = queue.next();
+        let second = queue.next();
+
+        assert_eq!(queue.previous(), first);
+        assert_eq!(queue.next(), second);
+    }
+
+    #[test]
+    fn revision_only_moves_when_the_tracks_change() {
+        let mut queue = queue(&["a", "b", "c"]);
+        let revision = queue.revision();
+
+        queue.next();
+        queue.skip();
+        queue.previous();
+        queue.cycle_shuffle();
+        assert_eq!(queue.revision(), revision);
+
+        queue.move_track(0, 1);
+        assert!(queue.revision() > revision);
+    }
+
+    #[test]
+    fn jump_to_out_of_range_does_nothing() {
+        let mut queue = queue(&["a"]);
+
+        assert_eq!(queue.jump_to(3), None);
+        assert_eq!(queue.current_index(), None);
+    }
+}
@@ -0,0 +1,202 @@
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+const CHUNK_FRAMES: usize = 1024;
+
+// Converts decoded audio to the output device's rate and channel layout
+pub struct Converter {
+    in_channels: usize,
+    out_channels: usize,
+    mix: Vec<Vec<f32>>,
+    resampler: Option<SincFixedIn<f32>>,
+    ratio: f64,
+    input: Vec<Vec<f32>>,
+    delay: usize,
+    frames_in: u64,
+    frames_out: u64,
+}
+
+impl Converter {
+    pub fn new(in_rate: u32, in_channels: usize, out_rate: u32, out_channels: usize) -> Self {
+        let ratio = out_rate as f64 / in_rate as f64;
+
+        let resampler = (in_rate != out_rate).then(|| {
+            let params = SincInterpolationParameters {
+                sinc_len: 256,
+                f_cutoff: 0.95,
+                interpolation: SincInterpolationType::Cubic,
+                oversampling_factor: 256,
+                window: WindowFunction::BlackmanHarris2,
+            };
+
+            SincFixedIn::<f32>::new(ratio, 1.0, params, CHUNK_FRAMES, out_channels)
+                .expect("valid resampler parameters")
+        });
+
+        let delay = resampler
+            .as_ref()
+            .map_or(0, |resampler| resampler.output_delay());
+
+        Self {
+            in_channels,
+            out_channels,
+            mix: mix_matrix(in_channels, out_channels),
+            resampler,
+            ratio,
+            input: vec![Vec::new(); out_channels],
+            delay,
+            frames_in: 0,
+            frames_out: 0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32], out: &mut Vec<f32>) {
+        if self.resampler.is_none() {
+            if self.in_channels == self.out_channels {
+                out.extend_from_slice(samples);
+            } else {
+                for frame in samples.chunks_exact(self.in_channels) {
+                    out.extend(self.mix.iter().map(|gains| mix_sample(gains, frame)));
+                }
+            }
+            return;
+        }
+
+        for frame in samples.chunks_exact(self.in_channels) {
+            for (channel, gains) in self.input.iter_mut().zip(&self.mix) {
+                channel.push(mix_sample(gains, frame));
+            }
+        }
+        self.frames_in += (samples.len() / self.in_channels) as u64;
+
+        while self.input[0].len() >= CHUNK_FRAMES {
+            let chunk: Vec<Vec<f32>> = self
+                .input
+                .iter_mut()
+                .map(|channel| channel.drain(..CHUNK_FRAMES).collect())
+                .collect();
+
+            if let Some(resampled) = self
+                .resampler
+                .as_mut()
+                .and_then(|resampler| resampler.process(&chunk, None).ok())
+            {
+                self.emit(&resampled, u64::MAX, out);
+            }
+        }
+    }
+
+    // Pushes out what's left in the resampler at the end of a track
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        let Some(mut resampler) = self.resampler.take() else {
+            return;
+        };
+
+        let expected = (self.frames_in as f64 * self.ratio).round() as u64;
+        let rest = std::mem::replace(&mut self.input, vec![Vec::new(); self.out_channels]);
+
+        if !rest[0].is_empty()
+            && let Ok(resampled) = resampler.process_partial(Some(&rest), None)
+        {
+            self.emit(&resampled, expected, out);
+        }
+
+        // The filter delay still holds the last few frames; feed silence until they're out
+        for _ in 0..8 {
+            if self.frames_out >= expected {
+                break;
+            }
+
+            match resampler.process_partial::<Vec<f32>>(None, None) {
+                Ok(resampled) => self.emit(&resampled, expected, out),
+                Err(_) => break,
+            }
+        }
+
+        self.resampler = Some(resampler);
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+            self.delay = resampler.output_delay();
+        }
+
+        for channel in self.input.iter_mut() {
+            channel.clear();
+        }
+        self.frames_in = 0;
+        self.frames_out = 0;
+    }
+
+    // Interleaves resampled frames, dropping the filter delay and anything past `limit`
+    fn emit(&mut self, planar: &[Vec<f32>], limit: u64, out: &mut Vec<f32>) {
+        let frames = planar[0].len();
+        let skip = self.delay.min(frames);
+        self.delay -= skip;
+
+        for index in skip..frames {
+            if self.frames_out >= limit {
+                break;
+            }
+
+            out.extend(planar.iter().map(|channel| channel[index]));
+            self.frames_out += 1;
+        }
+    }
+}
+
+fn mix_sample(gains: &[f32], frame: &[f32]) -> f32 {
+    gains
+        .iter()
+        .zip(frame)
+        .map(|(gain, sample)| gain * sample)
+        .sum()
+}
+
+// Gains from each input channel to each output channel, assuming the usual
+// FL, FR, FC, LFE, BL, BR, ... ordering
+fn mix_matrix(in_channels: usize, out_channels: usize) -> Vec<Vec<f32>> {
+    let mut matrix = vec![vec![0.0; in_channels]; out_channels];
+
+    if in_channels == 1 {
+        // Mono goes to both front speakers
+        for row in matrix.iter_mut().take(2) {
+            row[0] = 1.0;
+        }
+        return matrix;
+    }
+
+    if out_channels == 1 {
+        matrix[0].fill(1.0 / in_channels as f32);
+        return matrix;
+    }
+
+    for (channel, row) in matrix.iter_mut().enumerate().take(in_channels) {
+        row[channel] = 1.0;
+    }
+
+    // Fold the extra channels into the front pair: centre into both sides,
+    // LFE dropped, the rest alternating left and right
+    let fold = std::f32::consts::FRAC_1_SQRT_2;
+    for channel in out_channels..in_channels {
+        match channel {
+            2 => {
+                matrix[0][channel] = fold;
+                matrix[1][channel] = fold;
+            }
+            3 => {}
+            _ => matrix[channel % 2][channel] = fold,
+        }
+    }
+
+    for row in matrix.iter_mut() {
+        let total: f32 = row.iter().sum();
+        if total > 1.0 {
+            row.iter_mut().for_each(|gain| *gain /= total);
+        }
+    }
+
+    matrix
+}
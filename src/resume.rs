@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::toml_file::{self, state_path};
+
+// Only recordings at least this long get their position remembered,
+// which keeps ordinary songs from offering to resume
+const LONG_FORM: Duration = Duration::from_secs(10 * 60);
+
+// Where long recordings were left off, and what was playing last
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResumeState {
+    // Seconds into each file, keyed by path
+    positions: HashMap<String, f64>,
+    pub last_track: Option<PathBuf>,
+    pub last_position: f64,
+    pub queue_index: Option<usize>,
+}
+
+// The queue that was playing. It can run to the whole library, so unlike the
+// positions it's kept in a file of its own that's only written when it changes
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedQueue {
+    pub tracks: Vec<PathBuf>,
+}
+
+impl ResumeState {
+    pub fn load() -> Self {
+        toml_file::load(state_path("resume.toml").as_deref())
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = state_path("resume.toml").ok_or("No state directory found")?;
+        toml_file::save(&path, self)
+    }
+
+    pub fn position(&self, path: &Path) -> Option<Duration> {
+        let seconds = *self.positions.get(path.to_str()?)?;
+        Some(Duration::from_secs_f64(seconds))
+    }
+
+    // The very start and end of a recording aren't worth coming back to
+    pub fn remember(&mut self, path: &Path, position: Duration, total: Duration) {
+        let Some(key) = path.to_str() else {
+            return;
+        };
+
+        let near_start = position < Duration::from_secs(10);
+        let near_end = total.saturating_sub(position) < Duration::from_secs(30);
+
+        if total < LONG_FORM || near_start || near_end {
+            self.positions.remove(key);
+        } else {
+            self.positions
+                .insert(key.to_owned(), position.as_secs_f64());
+        }
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        if let Some(key) = path.to_str() {
+            self.positions.remove(key);
+        }
+    }
+}
+
+impl SavedQueue {
+    pub fn load() -> Self {
+        toml_file::load(state_path("queue.toml").as_deref())
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = state_path("queue.toml").ok_or("No state directory found")?;
+        toml_file::save(&path, self)
+    }
+}
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::playback::Volume;
+use crate::toml_file::{self, state_path};
+
+// How the app looked at the last quit. The queue and the track that was
+// playing are kept with the resume positions
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub volume: Volume,
+    pub search: String,
+    // Kept as a path, since the list may be ordered differently next time
+    pub selected: Option<PathBuf>,
+    pub paused: bool,
+}
+
+impl Session {
+    pub fn load() -> Self {
+        toml_file::load(state_path("session.toml").as_deref())
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = state_path("session.toml").ok_or("No state directory found")?;
+        toml_file::save(&path, self)
+    }
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::equalizer::EqSettings;
+use crate::playback::{CrossfadeSettings, ReplayGainMode};
+use crate::sink::OutputDevice;
+use crate::toml_file;
+
+// Preferences the app changes on its own, kept apart from the user's config
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub output_device: Option<OutputDevice>,
+    // Length of the fades around pausing, stopping and seeking
+    pub fade_ms: u32,
+    pub crossfade: CrossfadeSettings,
+    pub replay_gain: ReplayGainMode,
+    pub equalizer: EqSettings,
+    // Whether a restored session comes back paused even if it was playing
+    pub start_paused: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            output_device: None,
+            fade_ms: 30,
+            crossfade: CrossfadeSettings::default(),
+            replay_gain: ReplayGainMode::Track,
+            equalizer: EqSettings::default(),
+            start_paused: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn load() -> Self {
+        toml_file::load(settings_path().as_deref())
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = settings_path().ok_or("No config directory found")?;
+        toml_file::save(&path, self)
+    }
+}
+
+fn settings_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("tui_player").join("settings.toml"))
+}
@@ -0,0 +1,488 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::SignalSpec;
+
+use crate::playback::PlayerEvent;
+
+// The device callback converts this many frames at a time, through a buffer
+// set aside before the stream starts
+const SCRATCH_FRAMES: usize = 1024;
+
+// Fills a block of interleaved output samples, returning how many of them
+// are actual audio rather than padding
+pub type Render = Box<dyn FnMut(&mut [f32]) -> usize + Send>;
+
+// Somewhere for the decoded audio to go
+pub trait Sink {
+    // The format a track with this spec should be converted to
+    fn output_format(&self, spec: &SignalSpec) -> OutputFormat;
+
+    // Starts pulling audio through `render`, until the sink is dropped.
+    // Failures after that are reported through `events`
+    fn start(
+        &mut self,
+        format: OutputFormat,
+        render: Render,
+        events: Sender<PlayerEvent>,
+    ) -> Result<(), String>;
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SinkKind {
+    Device(Option<OutputDevice>),
+    // Throws the audio away, either at the pace a device would take it or
+    // as fast as it can be decoded
+    Null { realtime: bool },
+    Wav(PathBuf),
+}
+
+impl SinkKind {
+    // Sinks are opened on the decode thread, as cpal streams can't move between threads
+    pub fn open(&self) -> Result<Box<dyn Sink>, String> {
+        match self {
+            SinkKind::Device(device) => {
+                let device = find_output_device(device.as_ref())
+                    .ok_or("No audio output device available")?;
+                Ok(Box::new(CpalSink {
+                    device,
+                    stream: None,
+                }))
+            }
+            SinkKind::Null { realtime } => Ok(Box::new(NullSink {
+                realtime: *realtime,
+                thread: None,
+            })),
+            SinkKind::Wav(path) => Ok(Box::new(WavSink {
+                path: path.clone(),
+                thread: None,
+            })),
+        }
+    }
+}
+
+struct CpalSink {
+    device: cpal::Device,
+    stream: Option<cpal::Stream>,
+}
+
+impl Sink for CpalSink {
+    fn output_format(&self, spec: &SignalSpec) -> OutputFormat {
+        output_format(&self.device, spec)
+    }
+
+    fn start(
+        &mut self,
+        format: OutputFormat,
+        render: Render,
+        events: Sender<PlayerEvent>,
+    ) -> Result<(), String> {
+        let config = cpal::StreamConfig {
+            channels: format.channels as u16,
+            sample_rate: cpal::SampleRate(format.rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+
+        let stream = build_stream(&self.device, &config, format.sample_format, render, events)
+            .map_err(|e| format!("Error building audio output stream: {}", e))?;
+
+        stream
+            .play()
+            .map_err(|e| format!("Error starting audio playback: {}", e))?;
+
+        self.stream = Some(stream);
+        Ok(())
+    }
+}
+
+struct NullSink {
+    realtime: bool,
+    thread: Option<RenderThread>,
+}
+
+impl Sink for NullSink {
+    fn output_format(&self, spec: &SignalSpec) -> OutputFormat {
+        file_format(spec)
+    }
+
+    fn start(
+        &mut self,
+        format: OutputFormat,
+        render: Render,
+        _events: Sender<PlayerEvent>,
+    ) -> Result<(), String> {
+        self.thread = Some(RenderThread::spawn(format, self.realtime, render, |_| {}));
+        Ok(())
+    }
+}
+
+// Renders to a 32-bit float WAV file as fast as the audio can be decoded
+struct WavSink {
+    path: PathBuf,
+    thread: Option<RenderThread>,
+}
+
+impl Sink for WavSink {
+    fn output_format(&self, spec: &SignalSpec) -> OutputFormat {
+        file_format(spec)
+    }
+
+    fn start(
+        &mut self,
+        format: OutputFormat,
+        render: Render,
+        events: Sender<PlayerEvent>,
+    ) -> Result<(), String> {
+        let spec = hound::WavSpec {
+            channels: format.channels as u16,
+            sample_rate: format.rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+
+        let mut writer = Some(
+            hound::WavWriter::create(&self.path, spec)
+                .map_err(|e| format!("Error creating WAV file: {}", e))?,
+        );
+
+        // The writer finalizes the file when the render thread drops it
+        let write = move |block: &[f32]| {
+            if let Some(wav) = writer.as_mut()
+                && let Err(e) = block
+                    .iter()
+                    .try_for_each(|&sample| wav.write_sample(sample))
+            {
+                let _ = events.send(PlayerEvent::Error(format!("Error writing WAV file: {}", e)));
+                writer = None;
+            }
+        };
+
+        self.thread = Some(RenderThread::spawn(format, false, render, write));
+        Ok(())
+    }
+}
+
+// The file's own rate and channel layout, so nothing needs converting
+fn file_format(spec: &SignalSpec) -> OutputFormat {
+    OutputFormat {
+        rate: spec.rate,
+        channels: spec.channels.count(),
+        sample_format: SampleFormat::F32,
+    }
+}
+
+// Stands in for a device callback, pulling 10 ms blocks through `render`.
+// In real time it keeps to the clock like a device would; otherwise it
+// only takes what the decoder has ready, as fast as it comes
+struct RenderThread {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl RenderThread {
+    fn spawn(
+        format: OutputFormat,
+        realtime: bool,
+        mut render: Render,
+        mut write: impl FnMut(&[f32]) + Send + 'static,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+
+        let handle = thread::spawn(move || {
+            let frames = (format.rate as usize / 100).max(1);
+            let period = Duration::from_secs_f64(frames as f64 / format.rate.max(1) as f64);
+            let mut block = vec![0.0; frames * format.channels];
+            let mut deadline = Instant::now();
+
+            while !thread_stop.load(Ordering::SeqCst) {
+                let rendered = render(&mut block);
+
+                if realtime {
+                    write(&block);
+                    deadline += period;
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                } else if rendered > 0 {
+                    write(&block[..rendered]);
+                } else {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for RenderThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputDevice {
+    pub host: String,
+    pub name: String,
+}
+
+pub fn list_output_devices() -> Vec<OutputDevice> {
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|id| cpal::host_from_id(id).ok())
+        .flat_map(|host| {
+            let host_name = host.id().name().to_string();
+
+            host.output_devices()
+                .map(|devices| {
+                    devices
+                        .filter_map(|device| device.name().ok())
+                        .map(|name| OutputDevice {
+                            host: host_name.clone(),
+                            name,
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+// Falls back to the default device when the chosen one isn't around
+fn find_output_device(wanted: Option<&OutputDevice>) -> Option<cpal::Device> {
+    if let Some(wanted) = wanted {
+        let device = cpal::available_hosts()
+            .into_iter()
+            .filter(|id| id.name() == wanted.host)
+            .filter_map(|id| cpal::host_from_id(id).ok())
+            .find_map(|host| {
+                host.output_devices()
+                    .ok()?
+                    .find(|device| device.name().is_ok_and(|name| name == wanted.name))
+            });
+
+        if device.is_some() {
+            return device;
+        }
+    }
+
+    cpal::default_host().default_output_device()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct OutputFormat {
+    pub rate: u32,
+    pub channels: usize,
+    pub sample_format: SampleFormat,
+}
+
+// Picks the device config closest to the file in the device's own sample
+// format: the same channel count if possible, then the file's own rate or
+// the nearest one the device supports
+fn output_format(device: &cpal::Device, spec: &SignalSpec) -> OutputFormat {
+    let channels = spec.channels.count() as u16;
+    let sample_format = device
+        .default_output_config()
+        .map(|config| config.sample_format())
+        .unwrap_or(SampleFormat::F32);
+
+    let best = device.supported_output_configs().ok().and_then(|configs| {
+        configs
+            .filter(|range| range.sample_format() == sample_format)
+            .map(|range| {
+                let rate = spec
+                    .rate
+                    .clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+                (range.channels(), rate)
+            })
+            .min_by_key(|&(range_channels, rate)| {
+                (range_channels.abs_diff(channels), rate.abs_diff(spec.rate))
+            })
+    });
+
+    match best {
+        Some((channels, rate)) => OutputFormat {
+            rate,
+            channels: channels as usize,
+            sample_format,
+        },
+        None => OutputFormat {
+            rate: spec.rate,
+            channels: spec.channels.count(),
+            sample_format,
+        },
+    }
+}
+
+fn build_stream(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sample_format: SampleFormat,
+    render: Render,
+    events: Sender<PlayerEvent>,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    match sample_format {
+        SampleFormat::F32 => build_typed_stream::<f32>(device, config, render, events),
+        SampleFormat::F64 => build_typed_stream::<f64>(device, config, render, events),
+        SampleFormat::I8 => build_typed_stream::<i8>(device, config, render, events),
+        SampleFormat::I16 => build_typed_stream::<i16>(device, config, render, events),
+        SampleFormat::I32 => build_typed_stream::<i32>(device, config, render, events),
+        SampleFormat::I64 => build_typed_stream::<i64>(device, config, render, events),
+        SampleFormat::U8 => build_typed_stream::<u8>(device, config, render, events),
+        SampleFormat::U16 => build_typed_stream::<u16>(device, config, render, events),
+        SampleFormat::U32 => build_typed_stream::<u32>(device, config, render, events),
+        SampleFormat::U64 => build_typed_stream::<u64>(device, config, render, events),
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+// Renders in f32 and converts to the device's format, dithering on the way
+// down to 16 bits or fewer
+fn build_typed_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut render: Render,
+    events: Sender<PlayerEvent>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let mut dither = Dither::new(T::FORMAT);
+    let mut scratch = vec![0.0; SCRATCH_FRAMES * config.channels as usize];
+
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            render_converted(data, &mut scratch, &mut render, &mut dither);
+        },
+        move |err| {
+            let event = match err {
+                cpal::StreamError::DeviceNotAvailable => PlayerEvent::DeviceLost,
+                err => PlayerEvent::Error(format!("Error on the output audio stream: {}", err)),
+            };
+            let _ = events.send(event);
+        },
+        None,
+    )
+}
+
+// Fills a device buffer of any size a scratch buffer's worth at a time, as
+// nothing may be allocated on the audio thread. Once `render` runs short the
+// rest of the buffer is silence, so a starved ring counts as one underrun
+fn render_converted<T>(
+    data: &mut [T],
+    scratch: &mut [f32],
+    render: &mut Render,
+    dither: &mut Option<Dither>,
+) where
+    T: SizedSample + FromSample<f32>,
+{
+    let mut starved = false;
+
+    for out in data.chunks_mut(scratch.len()) {
+        let scratch = &mut scratch[..out.len()];
+        if starved {
+            scratch.fill(0.0);
+        } else {
+            starved = render(scratch) < scratch.len();
+        }
+
+        for (out, &sample) in out.iter_mut().zip(scratch.iter()) {
+            let sample = match dither.as_mut() {
+                Some(dither) => sample + dither.next(),
+                None => sample,
+            };
+            *out = T::from_sample(sample.clamp(-1.0, 1.0));
+        }
+    }
+}
+
+// Triangular (TPDF) dither of one LSB at the output bit depth
+struct Dither {
+    lsb: f32,
+    state: u32,
+}
+
+impl Dither {
+    fn new(format: SampleFormat) -> Option<Self> {
+        let bits = format.sample_size() as i32 * 8;
+
+        (!format.is_float() && bits <= 16).then(|| Self {
+            lsb: 2f32.powi(1 - bits),
+            state: 0x9E37_79B9,
+        })
+    }
+
+    fn next(&mut self) -> f32 {
+        (self.uniform() - self.uniform()) * self.lsb
+    }
+
+    // xorshift32, mapped to 0..1
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A device asking for more than the scratch buffer holds gets every
+    // sample, without the scratch buffer ever growing
+    #[test]
+    fn large_device_buffers_are_rendered_in_chunks() {
+        let mut scratch = vec![0.0; 256];
+        let mut next = 0.0;
+        let mut render: Render = Box::new(move |block: &mut [f32]| {
+            assert!(block.len() <= 256);
+            for sample in block.iter_mut() {
+                next += 1.0 / 4096.0;
+                *sample = next;
+            }
+            block.len()
+        });
+
+        let mut data = vec![0.0f32; 1000];
+        render_converted(&mut data, &mut scratch, &mut render, &mut None);
+
+        assert_eq!(scratch.len(), 256);
+        assert_eq!(data[0], 1.0 / 4096.0);
+        assert_eq!(data[999], 1000.0 / 4096.0);
+    }
+
+    #[test]
+    fn a_short_render_leaves_the_rest_of_the_buffer_silent() {
+        let mut scratch = vec![0.0; 256];
+        let mut calls = 0;
+        let mut render: Render = Box::new(move |block: &mut [f32]| {
+            calls += 1;
+            assert_eq!(calls, 1, "rendered again after running short");
+            block.fill(0.0);
+            block[..100].fill(0.5);
+            100
+        });
+
+        let mut data = vec![1i16; 1000];
+        render_converted(&mut data, &mut scratch, &mut render, &mut None);
+
+        assert!(data[..100].iter().all(|&sample| sample > 0));
+        assert!(data[100..].iter().all(|&sample| sample == 0));
+    }
+}
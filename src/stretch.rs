@@ -0,0 +1,269 @@
+use crate::resample::Converter;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Tempo {
+    pub speed: f32,
+    pub semitones: f32,
+}
+
+impl Default for Tempo {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            semitones: 0.0,
+        }
+    }
+}
+
+impl Tempo {
+    fn pitch(self) -> f64 {
+        2f64.powf(self.semitones as f64 / 12.0)
+    }
+}
+
+// Changes speed and pitch independently: the time stretch runs at
+// speed / pitch, then resampling speeds it up the rest of the way and
+// raises the pitch by the same factor
+pub struct TimeStretch {
+    rate: u32,
+    channels: usize,
+    tempo: Tempo,
+    wsola: Option<Wsola>,
+    converter: Option<Converter>,
+    scratch: Vec<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(rate: u32, channels: usize) -> Self {
+        Self {
+            rate,
+            channels,
+            tempo: Tempo::default(),
+            wsola: None,
+            converter: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn tempo(&self) -> Tempo {
+        self.tempo
+    }
+
+    // Whatever the old settings still hold is played out into `out` first
+    pub fn set_tempo(&mut self, tempo: Tempo, out: &mut Vec<f32>) {
+        if tempo == self.tempo {
+            return;
+        }
+
+        self.flush(out);
+        self.tempo = tempo;
+
+        let pitch = tempo.pitch();
+        let stretch = tempo.speed as f64 / pitch;
+
+        self.wsola =
+            ((stretch - 1.0).abs() > 1e-6).then(|| Wsola::new(self.rate, self.channels, stretch));
+        self.converter = (tempo.semitones != 0.0).then(|| {
+            let rate = (self.rate as f64 * pitch).round() as u32;
+            Converter::new(rate, self.channels, self.rate, self.channels)
+        });
+    }
+
+    pub fn process(&mut self, samples: &mut Vec<f32>) {
+        if let Some(wsola) = self.wsola.as_mut() {
+            self.scratch.clear();
+            wsola.process(samples, &mut self.scratch);
+            std::mem::swap(samples, &mut self.scratch);
+        }
+
+        if let Some(converter) = self.converter.as_mut() {
+            self.scratch.clear();
+            converter.process(samples, &mut self.scratch);
+            std::mem::swap(samples, &mut self.scratch);
+        }
+    }
+
+    pub fn flush(&mut self, out: &mut Vec<f32>) {
+        let mut tail = Vec::new();
+
+        if let Some(wsola) = self.wsola.as_mut() {
+            wsola.flush(&mut tail);
+        }
+
+        if let Some(converter) = self.converter.as_mut() {
+            let mut converted = Vec::new();
+            converter.process(&tail, &mut converted);
+            converter.flush(&mut converted);
+            tail = converted;
+        }
+
+        out.extend_from_slice(&tail);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(wsola) = self.wsola.as_mut() {
+            wsola.reset();
+        }
+
+        if let Some(converter) = self.converter.as_mut() {
+            converter.reset();
+        }
+    }
+}
+
+// Waveform-similarity overlap-add: 40 ms Hann windows laid down every 20 ms
+// of output, each taken from around where the stretched timeline says it
+// should come from, nudged to line up with the audio already laid down
+struct Wsola {
+    channels: usize,
+    stretch: f64,
+    window: Vec<f32>,
+    hop: usize,
+    tolerance: usize,
+    input: Vec<f32>,
+    // Absolute frame index of the first frame still held in `input`
+    input_start: usize,
+    position: f64,
+    previous: Option<usize>,
+    overlap: Vec<f32>,
+}
+
+impl Wsola {
+    fn new(rate: u32, channels: usize, stretch: f64) -> Self {
+        let hop = (rate as usize / 50).max(1);
+        let length = hop * 2;
+
+        // Periodic Hann windows at half overlap sum to exactly one
+        let window = (0..length)
+            .map(|n| {
+                let phase = 2.0 * std::f32::consts::PI * n as f32 / length as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        Self {
+            channels,
+            stretch,
+            window,
+            hop,
+            tolerance: (rate as usize / 100).max(1),
+            input: Vec::new(),
+            input_start: 0,
+            position: 0.0,
+            previous: None,
+            overlap: vec![0.0; length * channels],
+        }
+    }
+
+    fn process(&mut self, samples: &[f32], out: &mut Vec<f32>) {
+        self.input.extend_from_slice(samples);
+
+        let length = self.window.len();
+        let channels = self.channels;
+
+        loop {
+            let nominal = self.position.round() as usize;
+            let natural = self.previous.map(|previous| previous + self.hop);
+            let needed = (nominal + self.tolerance + length).max(natural.unwrap_or(0) + length);
+
+            if needed > self.input_start + self.input.len() / channels {
+                break;
+            }
+
+            let best = match natural {
+                Some(natural) => self.best_match(nominal, natural),
+                None => nominal,
+            };
+
+            let start = (best - self.input_start) * channels;
+            let segment = &self.input[start..start + length * channels];
+            for (index, (sum, &sample)) in self.overlap.iter_mut().zip(segment).enumerate() {
+                *sum += sample * self.window[index / channels];
+            }
+
+            let done = self.hop * channels;
+            out.extend_from_slice(&self.overlap[..done]);
+            self.overlap.copy_within(done.., 0);
+            let kept = self.overlap.len() - done;
+            self.overlap[kept..].fill(0.0);
+
+            self.previous = Some(best);
+            self.position += self.hop as f64 * self.stretch;
+
+            // Nothing before the next search range or continuation is needed again
+            let keep_from = (self.position.round() as usize)
+                .saturating_sub(self.tolerance)
+                .min(best + self.hop);
+            let drop = keep_from.saturating_sub(self.input_start);
+            self.input.drain(..drop * channels);
+            self.input_start += drop;
+        }
+    }
+
+    // Finds where around `nominal` the audio best continues the previous
+    // window, searching coarsely first and then around the best coarse hit
+    fn best_match(&self, nominal: usize, natural: usize) -> usize {
+        let lowest = nominal.saturating_sub(self.tolerance).max(self.input_start);
+        let highest = nominal + self.tolerance;
+
+        let coarse = self.most_similar((lowest..=highest).step_by(4), natural);
+        let fine_range = coarse.saturating_sub(3).max(lowest)..=(coarse + 3).min(highest);
+        self.most_similar(fine_range, natural)
+    }
+
+    fn most_similar(&self, candidates: impl Iterator<Item = usize>, natural: usize) -> usize {
+        candidates
+            .map(|candidate| (candidate, self.similarity(candidate, natural)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(natural, |(candidate, _)| candidate)
+    }
+
+    // Normalized correlation of the overlapping half, on every fourth frame
+    // of a mono mix to keep the search cheap
+    fn similarity(&self, candidate: usize, natural: usize) -> f32 {
+        let channels = self.channels;
+        let mono = |frame: usize| -> f32 {
+            let start = (frame - self.input_start) * channels;
+            self.input[start..start + channels].iter().sum()
+        };
+
+        let mut product = 0.0;
+        let mut energy = 0.0;
+        for offset in (0..self.hop).step_by(4) {
+            let a = mono(candidate + offset);
+            let b = mono(natural + offset);
+            product += a * b;
+            energy += a * a;
+        }
+
+        product / (energy.sqrt() + 1e-9)
+    }
+
+    // Pads with silence to push the held audio out, keeping only as much
+    // output as the real input accounts for
+    fn flush(&mut self, out: &mut Vec<f32>) {
+        let input_end = self.input_start + self.input.len() / self.channels;
+        let remaining = ((input_end as f64 - self.position).max(0.0) / self.stretch) as usize;
+
+        let before = out.len();
+        let padding = vec![0.0; (self.window.len() + self.tolerance * 2) * self.channels];
+        while (out.len() - before) / self.channels < remaining {
+            let emitted = out.len();
+            self.process(&padding, out);
+            if out.len() == emitted {
+                break;
+            }
+        }
+
+        out.truncate(before + remaining * self.channels);
+    }
+
+    fn reset(&mut self) {
+        self.input.clear();
+        self.input_start = 0;
+        self.position = 0.0;
+        self.previous = None;
+        self.overlap.fill(0.0);
+    }
+}
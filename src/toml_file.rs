@@ -0,0 +1,29 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The files the app keeps for itself: anything missing or unreadable just
+// means starting over from the defaults
+pub fn load<T: DeserializeOwned + Default>(path: Option<&Path>) -> T {
+    path.and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+pub fn save<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml::to_string(value)?)?;
+
+    Ok(())
+}
+
+// Falls back to the local data directory where there's no XDG state directory
+pub fn state_path(file: &str) -> Option<PathBuf> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join("tui_player").join(file))
+}
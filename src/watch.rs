@@ -0,0 +1,189 @@
+use globset::GlobSet;
+use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::LibraryRoot;
+use crate::library::Library;
+use crate::music_manipulation::{is_music, music_files};
+use crate::playback::EXIT_NOW;
+
+// A burst of events, like an album being copied in, is handled together
+// once it has been quiet for this long
+const SETTLE: Duration = Duration::from_millis(500);
+
+pub enum LibraryChange {
+    // A file that's new to the library, or whose tags may have changed
+    Added(PathBuf),
+    // A file, or a directory with everything under it, that's gone
+    Removed(PathBuf),
+    Error(String),
+}
+
+// Watches the library roots and keeps the index in step with them,
+// reporting each change once the index has it
+pub struct LibraryWatcher {
+    _watchers: Vec<RecommendedWatcher>,
+    changes: Receiver<LibraryChange>,
+}
+
+impl LibraryWatcher {
+    pub fn start(roots: Vec<LibraryRoot>) -> Self {
+        let (event_tx, event_rx) = mpsc::channel();
+        let (change_tx, changes) = mpsc::channel();
+
+        // Each root gets its own watcher, as following symlinks is set per watcher
+        let mut watchers = Vec::new();
+        for root in &roots {
+            let config = Config::default().with_follow_symlinks(root.follow_symlinks);
+            let watched =
+                RecommendedWatcher::new(event_tx.clone(), config).and_then(|mut watcher| {
+                    watcher.watch(&root.path, RecursiveMode::Recursive)?;
+                    Ok(watcher)
+                });
+
+            match watched {
+                Ok(watcher) => watchers.push(watcher),
+                Err(e) => {
+                    let _ = change_tx.send(LibraryChange::Error(format!(
+                        "Can't watch {}: {}",
+                        root.path.display(),
+                        e
+                    )));
+                }
+            }
+        }
+        drop(event_tx);
+
+        thread::spawn(move || handle_events(&roots, &event_rx, &change_tx));
+
+        Self {
+            _watchers: watchers,
+            changes,
+        }
+    }
+
+    pub fn changes(&self) -> Vec<LibraryChange> {
+        self.changes.try_iter().collect()
+    }
+}
+
+fn handle_events(
+    roots: &[LibraryRoot],
+    events: &Receiver<notify::Result<Event>>,
+    changes: &Sender<LibraryChange>,
+) {
+    let library = match Library::open() {
+        Ok(library) => library,
+        Err(e) => {
+            let _ = changes.send(LibraryChange::Error(format!(
+                "Can't watch the library: {}",
+                e
+            )));
+            return;
+        }
+    };
+    let roots: Vec<(&LibraryRoot, GlobSet)> = roots
+        .iter()
+        .map(|root| {
+            (
+                root,
+                root.exclude_set().unwrap_or_else(|_| GlobSet::empty()),
+            )
+        })
+        .collect();
+
+    // Ends once every watcher has been dropped
+    while let Ok(first) = events.recv() {
+        let mut paths = BTreeSet::new();
+        let mut next = Ok(first);
+
+        loop {
+            match next {
+                // Files being opened or read say nothing about the library
+                Ok(Ok(event)) if !matches!(event.kind, EventKind::Access(_)) => {
+                    paths.extend(event.paths);
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    let _ = changes.send(LibraryChange::Error(format!("Library watch: {}", e)));
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            next = events.recv_timeout(SETTLE);
+        }
+
+        if EXIT_NOW.load(Ordering::SeqCst) {
+            return;
+        }
+
+        for path in paths {
+            let Some((root, excludes)) =
+                roots.iter().find(|(root, _)| path.starts_with(&root.path))
+            else {
+                continue;
+            };
+            if excluded(&root.path, excludes, &path) {
+                continue;
+            }
+
+            if path.is_dir() {
+                // A directory moved in brings everything under it along, but
+                // the root's excludes still apply beneath it
+                let moved_in = LibraryRoot {
+                    path: path.clone(),
+                    exclude: Vec::new(),
+                    follow_symlinks: root.follow_symlinks,
+                };
+                for file in music_files(&[moved_in]) {
+                    if !excluded(&root.path, excludes, &file) {
+                        add(&library, file, changes);
+                    }
+                }
+            } else if path.is_file() {
+                if is_music(&path) {
+                    add(&library, path, changes);
+                }
+            } else {
+                let _ = library.remove(&path);
+                let _ = changes.send(LibraryChange::Removed(path));
+            }
+        }
+    }
+}
+
+// Like a scan, files whose stamp hasn't changed are left as they are
+fn add(library: &Library, path: PathBuf, changes: &Sender<LibraryChange>) {
+    match library.update(&path) {
+        Ok(true) => {
+            let _ = changes.send(LibraryChange::Added(path));
+        }
+        Ok(false) => {}
+        Err(e) => {
+            let _ = changes.send(LibraryChange::Error(format!(
+                "Can't index {}: {}",
+                path.display(),
+                e
+            )));
+            let _ = changes.send(LibraryChange::Added(path));
+        }
+    }
+}
+
+// Matches the way a scan skips excluded directories, by checking every
+// directory between the root and the file too
+fn excluded(root: &Path, excludes: &GlobSet, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return false;
+    };
+
+    relative
+        .ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| excludes.is_match(ancestor))
+}